@@ -0,0 +1,245 @@
+use std::io;
+use std::net::SocketAddr;
+use std::cmp;
+use std::sync::Arc;
+use std::time::Instant;
+use mio::net::TcpStream;
+use diameter::framing::{FrameDecoder, FrameError};
+use diameter::avps;
+use diameter::message_builder::{patch_avp_u32, MessageTemplate};
+use diameter::message_header::{HopByHop, MESSAGE_HEADER_SIZE};
+use limits::ConnectionPermit;
+use worker::ConnectionHandle;
+use stats::Stats;
+use transport::Transport;
+use tls;
+use {handle_packet, reject_invalid_header, ClientError, Config, Endpoint, Peer, PeerState, Shared};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+
+/// Per-connection state driven by a worker's event loop. The socket is
+/// non-blocking, so incoming data is collected by a `FrameDecoder` until it
+/// holds complete messages.
+pub struct Client {
+    transport: Transport,
+    address: SocketAddr,
+    peer: Peer,
+    decoder: FrameDecoder,
+    write_buffer: Vec<u8>,
+    write_pos: usize,
+    unwritten_answers: u64,
+    closing: Option<ClientError>,
+    last_read: Instant,
+    /// When the first bytes of the message not yet completely received
+    /// arrived, if there is one.
+    message_started: Option<Instant>,
+    _permit: ConnectionPermit,
+}
+
+impl Client {
+    /// Sets up a connection, speaking TLS from the start if `tls` is set.
+    pub fn new(config: &Config, stream: TcpStream, connection: ConnectionHandle, endpoint: Arc<Endpoint>, shared: &Shared, tls: bool, permit: ConnectionPermit) -> io::Result<Client> {
+        let address = connection.address();
+        let transport = Transport::new(stream, if tls { config.tls.as_ref() } else { None })?;
+        let peer = Peer::new(endpoint, shared, connection, transport.is_tls());
+        let read_buffer_size = cmp::max(READ_BUFFER_SIZE, config.max_message_size as usize);
+        Ok(Client {
+            transport,
+            address,
+            peer,
+            decoder: FrameDecoder::new(read_buffer_size, config.max_message_size - MESSAGE_HEADER_SIZE),
+            write_buffer: Vec::with_capacity(MAX_PENDING_OUTPUT),
+            write_pos: 0,
+            unwritten_answers: 0,
+            closing: None,
+            last_read: Instant::now(),
+            message_started: None,
+            _permit: permit,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stream(&mut self) -> &mut TcpStream {
+        self.transport.stream()
+    }
+
+    /// Called on every readiness event. Answers to all complete requests in
+    /// the input are collected and written with a single call once the input
+    /// runs dry or `MAX_PENDING_OUTPUT` is reached. Nothing more is read while
+    /// output is pending, which keeps a peer that does not read its answers
+    /// from growing our buffers.
+    pub fn process(&mut self, config: &Config, stats: &Stats) -> Result<(), ClientError> {
+        loop {
+            self.handle_frames(config, stats)?;
+            self.flush(config, stats)?;
+            if self.has_pending_output() {
+                return Ok(());
+            }
+            match self.transport.read(self.decoder.read_space()) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    self.decoder.commit(n);
+                    self.last_read = Instant::now();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Handles buffered requests until the output is full, the connection
+    /// is to be closed, or the next bytes belong to an in-band TLS handshake.
+    fn handle_frames(&mut self, config: &Config, stats: &Stats) -> Result<(), ClientError> {
+        if self.peer.tls && self.peer.certificate_names.is_none() {
+            self.check_certificate()?;
+        }
+        while self.closing.is_none() && !self.peer.start_tls && self.write_buffer.len() - self.write_pos < MAX_PENDING_OUTPUT {
+            let output_len = self.write_buffer.len();
+            let result = match self.decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    self.message_started = None;
+                    handle_packet(config, stats, &mut self.peer, &frame.header, frame.payload, &mut self.write_buffer)
+                }
+                Ok(None) => {
+                    if !self.decoder.is_empty() && self.message_started.is_none() {
+                        self.message_started = Some(self.last_read);
+                    }
+                    return Ok(());
+                }
+                Err(FrameError::InvalidHeader(header, e)) => reject_invalid_header(&self.peer.endpoint, &header, e, &mut self.write_buffer),
+                Err(e) => return Err(e.into()),
+            };
+            if let Err(e) = result {
+                if !e.is_answered() {
+                    return Err(e);
+                }
+                self.closing = Some(e);
+            }
+            if self.write_buffer.len() > output_len {
+                self.unwritten_answers += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Called regularly by the worker. Sends a DWR when the watchdog is due,
+    /// and fails if the peer has been silent for too long, is too slow in
+    /// sending the rest of a message, or did not answer enough DWRs.
+    pub fn on_tick(&mut self, config: &Config, stats: &Stats, now: Instant) -> Result<(), ClientError> {
+        if let Some(timeout) = config.idle_timeout {
+            if now.duration_since(self.last_read) >= timeout {
+                return Err(ClientError::IdleTimeout);
+            }
+        }
+        if let (Some(timeout), Some(started)) = (config.message_timeout, self.message_started) {
+            if now.duration_since(started) >= timeout {
+                return Err(ClientError::MessageTimeout);
+            }
+        }
+        if let Some(interval) = config.watchdog_interval {
+            if self.peer.state != PeerState::WaitCer && self.peer.watchdog.is_due(now, self.last_read, interval) {
+                let missed = self.peer.watchdog.missed();
+                if missed >= config.watchdog_max_missed {
+                    return Err(ClientError::WatchdogFailed(missed));
+                }
+                if missed > 0 {
+                    println!("[{}] No DWA within Tw, {} of {} missed", self.address, missed, config.watchdog_max_missed);
+                }
+                let hop_by_hop = self.write_request(|endpoint| &endpoint.dwr);
+                self.peer.watchdog.sent(hop_by_hop, now);
+                self.flush(config, stats)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the peer to disconnect by sending a DPR with the given
+    /// Disconnect-Cause. The connection is closed once the DPA arrives. Peers
+    /// that have not completed the capabilities exchange are not asked and
+    /// fail right away.
+    pub fn disconnect(&mut self, config: &Config, stats: &Stats, cause: u32) -> Result<(), ClientError> {
+        if self.peer.state != PeerState::Open || self.closing.is_some() {
+            self.transport.close();
+            return Err(ClientError::Disconnected);
+        }
+        let start = self.write_buffer.len();
+        let hop_by_hop = self.write_request(|endpoint| &endpoint.dpr);
+        patch_avp_u32(&mut self.write_buffer[start..], avps::DISCONNECT_CAUSE, cause);
+        self.peer.state = PeerState::Closing(hop_by_hop);
+        self.flush(config, stats)
+    }
+
+    fn write_request(&mut self, template: fn(&Endpoint) -> &MessageTemplate) -> HopByHop {
+        let (hop_by_hop, end_to_end) = self.peer.request_ids.next();
+        template(&self.peer.endpoint).write(&mut self.write_buffer, hop_by_hop, end_to_end);
+        hop_by_hop
+    }
+
+    pub fn close(&mut self) {
+        self.transport.close();
+    }
+
+    /// Picks up the client certificate once the handshake is done. A peer
+    /// that upgraded in-band already sent its Origin-Host in the CER, so it is
+    /// checked here instead of when answering that.
+    fn check_certificate(&mut self) -> Result<(), ClientError> {
+        if let Some(cert) = self.transport.peer_certificate() {
+            self.peer.certificate_names = Some(tls::certificate_names(cert));
+            if !self.peer.origin_host.is_empty() && !self.peer.certificate_matches(&self.peer.origin_host) {
+                return Err(ClientError::CertificateMismatch(self.peer.origin_host.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn has_pending_output(&self) -> bool {
+        self.write_pos < self.write_buffer.len() || self.transport.has_pending_output()
+    }
+
+    fn flush(&mut self, config: &Config, stats: &Stats) -> Result<(), ClientError> {
+        while self.write_pos < self.write_buffer.len() {
+            match self.transport.write(&self.write_buffer[self.write_pos..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => {
+                    self.write_pos += n;
+                    stats.record_write(self.unwritten_answers);
+                    self.unwritten_answers = 0;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.write_buffer.clear();
+        self.write_pos = 0;
+        self.transport.flush()?;
+        if self.has_pending_output() {
+            return Ok(());
+        }
+        if let Some(e) = self.closing.take() {
+            self.transport.close();
+            return Err(e);
+        }
+        if self.peer.start_tls {
+            self.start_tls(config)?;
+        }
+        Ok(())
+    }
+
+    /// Switches to TLS once the CEA that agreed to it has been sent.
+    fn start_tls(&mut self, config: &Config) -> Result<(), ClientError> {
+        self.peer.start_tls = false;
+        if let Some(ref tls_config) = config.tls {
+            let received = self.decoder.take_buffered();
+            self.transport.start_tls(tls_config, &received)?;
+            self.peer.tls = true;
+            println!("[{}] Started in-band TLS", self.address);
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use std::result::Result;
 use byteorder::{ByteOrder, BigEndian};
-use super::ParseError;
+use super::{ParseError, ParseErrorKind};
 use super::avps::AvpId;
 use super::avp_flags;
 use super::avp_flags::AvpFlags;
@@ -18,21 +18,21 @@ pub struct AvpHeader {
 impl AvpHeader {
     pub fn parse(buffer: &[u8]) -> Result<AvpHeader, ParseError> {
         if buffer.len() < NORMAL_AVP_HEADER_SIZE {
-            return Err(ParseError::InvalidAvpLength);
+            return Err(ParseErrorKind::InvalidAvpLength.into());
         }
         let code = BigEndian::read_u32(&buffer[0..4]);
         let flags_and_length = BigEndian::read_u32(&buffer[4..8]);
-        let flags = AvpFlags::from_bits((flags_and_length >> 24) as u8).ok_or(ParseError::InvalidAvpBits)?;
+        let flags = AvpFlags::from_bits((flags_and_length >> 24) as u8).ok_or(ParseErrorKind::InvalidAvpBits)?;
         let vendor_id = if flags.contains(avp_flags::VENDOR) {
             if buffer.len() < VENDOR_AVP_HEADER_SIZE {
-                return Err(ParseError::InvalidAvpLength);
+                return Err(ParseErrorKind::InvalidAvpLength.into());
             }
             BigEndian::read_u32(&buffer[8..12])
         } else {
             0
         };
         let length = flags_and_length & 0x00FFFFFF;
-        Ok(AvpHeader { avp_id: AvpId { code: code, vendor_id: vendor_id }, flags: flags, length: length })
+        Ok(AvpHeader { avp_id: AvpId { code, vendor_id }, flags, length })
     }
 
     pub fn header_len(&self) -> usize {
@@ -0,0 +1,118 @@
+use std::iter;
+use super::{ParseError, ParseErrorKind};
+use super::avps::AvpId;
+use super::avp_header::AvpHeader;
+use super::avp_parsers::round_up;
+
+/// Iterates over the AVPs in a message payload or the value of a grouped
+/// AVP, yielding the header and value of each without copying. A malformed
+/// AVP ends the iteration, and the error is kept for `finish`.
+pub struct AvpIter<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+    error: Option<ParseError>,
+}
+
+impl<'a> AvpIter<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        AvpIter { buffer, pos: 0, error: None }
+    }
+
+    /// Returns the error that ended the iteration, if any, with the
+    /// malformed AVP as the Failed-AVP when it is known.
+    pub fn finish(self) -> Result<(), ParseError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// The value of the first AVP with the given id.
+    #[allow(dead_code)]
+    pub fn find_first(mut self, avp_id: AvpId) -> Option<&'a [u8]> {
+        self.find(|(header, _)| header.avp_id == avp_id).map(|(_, value)| value)
+    }
+
+    /// The values of all AVPs with the given id, in order.
+    #[allow(dead_code)]
+    pub fn find_all(self, avp_id: AvpId) -> impl Iterator<Item = &'a [u8]> {
+        self.filter(move |(header, _)| header.avp_id == avp_id).map(|(_, value)| value)
+    }
+
+    /// Also yields where each AVP starts in the buffer, for callers that
+    /// copy or patch the encoded AVPs.
+    pub fn with_offsets(mut self) -> impl Iterator<Item = (usize, AvpHeader, &'a [u8])> {
+        iter::from_fn(move || {
+            let start = self.pos;
+            self.next().map(|(header, value)| (start, header, value))
+        })
+    }
+
+    /// Descends into the first grouped AVP with the given id.
+    #[allow(dead_code)]
+    pub fn find_grouped(self, avp_id: AvpId) -> Option<AvpIter<'a>> {
+        self.find_first(avp_id).map(AvpIter::new)
+    }
+}
+
+impl<'a> Iterator for AvpIter<'a> {
+    type Item = (AvpHeader, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buffer.len() || self.error.is_some() {
+            return None;
+        }
+        let remaining = &self.buffer[self.pos..];
+        let header = match AvpHeader::parse(remaining) {
+            Ok(header) => header,
+            Err(e) => {
+                self.error = Some(e);
+                return None;
+            }
+        };
+        let start = header.header_len();
+        let padded_len = round_up(header.total_len());
+        if header.total_len() < start || padded_len > remaining.len() {
+            let available = &remaining[start.min(remaining.len())..];
+            self.error = Some(ParseError::from(ParseErrorKind::InvalidAvpLength).in_avp(&header, available));
+            return None;
+        }
+        self.pos += padded_len;
+        Some((header, &remaining[start..header.total_len()]))
+    }
+}
+
+#[test]
+pub fn finds_avps_without_copying() {
+    use gy;
+    use super::avps;
+    use super::avp_parsers::parse_u32;
+    use super::message_builder::test_payload;
+    let payload = &test_payload(&|mb| {
+        mb.put_avp_bytes(avps::ORIGIN_HOST, b"pcef")
+            .put_avp_u32(gy::avps::RATING_GROUP, 1);
+        mb.begin_avp(gy::avps::MULTIPLE_SERVICES_CC)
+            .put_avp_u32(gy::avps::RATING_GROUP, 2)
+            .begin_avp(gy::avps::GRANTED_SERVICE_UNIT)
+            .put_avp_u32(gy::avps::CC_TIME, 60);
+        mb.put_avp_u32(gy::avps::RATING_GROUP, 3);
+    });
+
+    assert_eq!(Some(&b"pcef"[..]), AvpIter::new(payload).find_first(avps::ORIGIN_HOST));
+    assert_eq!(None, AvpIter::new(payload).find_first(avps::DESTINATION_HOST));
+    let rating_groups: Vec<u32> = AvpIter::new(payload).find_all(gy::avps::RATING_GROUP).map(|value| parse_u32(value).unwrap()).collect();
+    assert_eq!(vec![1, 3], rating_groups);
+    let cc_time = AvpIter::new(payload).find_grouped(gy::avps::MULTIPLE_SERVICES_CC)
+        .and_then(|avps| avps.find_grouped(gy::avps::GRANTED_SERVICE_UNIT))
+        .and_then(|avps| avps.find_first(gy::avps::CC_TIME));
+    assert_eq!(Some(&[0, 0, 0, 60][..]), cc_time);
+    assert!(AvpIter::new(payload).finish().is_ok());
+    let offsets: Vec<usize> = AvpIter::new(payload).with_offsets().map(|(start, _, _)| start).collect();
+    assert_eq!(vec![0, 12, 24, 64], offsets);
+
+    let mut avps = AvpIter::new(&payload[..payload.len() - 4]);
+    assert_eq!(3, avps.by_ref().count());
+    let error = avps.finish().unwrap_err();
+    assert_eq!(ParseErrorKind::InvalidAvpLength, error.kind);
+    assert_eq!(gy::avps::RATING_GROUP, error.failed_avp.unwrap().avp_id);
+}
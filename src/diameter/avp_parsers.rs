@@ -1,34 +1,345 @@
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::result::Result;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use byteorder::{ByteOrder, BigEndian};
-use super::ParseError;
+use super::{ParseError, ParseErrorKind};
 use super::avps::AvpId;
-use super::avp_header::AvpHeader;
+use super::avp_flags;
+use super::avp_iter::AvpIter;
 
-pub type ParserFn<T> = Fn(AvpId, &[u8], &mut T) -> Result<(), ParseError>;
+pub type ParserFn<T> = dyn Fn(AvpId, &[u8], &mut T) -> Result<(), ParseError>;
 
-pub fn parse_avps<T>(buffer: &[u8], avp_parser: &ParserFn<T>, result: &mut T) -> Result<(), ParseError> {
-    let mut pos = 0;
-    while pos < buffer.len() {
-        let header = AvpHeader::parse(&buffer[pos..])?;
-        let padded_len = round_up(header.total_len());
-        if padded_len > buffer.len() - pos {
-            return Err(ParseError::InvalidAvpLength);
+/// Hands the AVPs in `buffer` that are among `known_avps`, the ones the
+/// command or grouped AVP is defined with, to `avp_parser`. Others are
+/// skipped, unless they have the M bit set and parsing is not `lenient`,
+/// which fails with DIAMETER_AVP_UNSUPPORTED.
+pub fn parse_avps<T>(buffer: &[u8], known_avps: &[AvpId], lenient: bool, avp_parser: &ParserFn<T>, result: &mut T) -> Result<(), ParseError> {
+    let mut avps = AvpIter::new(buffer);
+    for (header, payload) in avps.by_ref() {
+        if known_avps.contains(&header.avp_id) {
+            avp_parser(header.avp_id, payload, result).map_err(|e| e.in_avp(&header, payload))?;
+        } else if !lenient && header.flags.contains(avp_flags::MANDATORY) {
+            return Err(ParseError::from(ParseErrorKind::AvpUnsupported).in_avp(&header, payload));
         }
-        let start = pos + header.header_len();
-        let end = pos + header.total_len();
-        avp_parser(header.avp_id, &buffer[start..end], result)?;
-        pos += padded_len;
     }
-    Ok(())
+    avps.finish()
 }
 
-fn round_up(value: usize) -> usize {
+pub fn round_up(value: usize) -> usize {
     (value + 3) & 0xFFFFFFFFFFFFFFFC
 }
 
-pub fn parse_u32(buffer: &[u8]) -> Result<u32, ParseError> {
-    if buffer.len() != 4 {
-        return Err(ParseError::InvalidAvpLength);
+/// Seconds from the NTP epoch, 1900, to the Unix one.
+const NTP_UNIX_OFFSET: u64 = 2208988800;
+
+/// Address families of RFC 6733 section 4.3.1, as assigned by IANA.
+pub mod address_family {
+    pub const IPV4: u16 = 1;
+    pub const IPV6: u16 = 2;
+    pub const E164: u16 = 8;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address<'a> {
+    Ip(IpAddr),
+    /// The digits of a telephone number.
+    E164(&'a str),
+}
+
+/// A DiameterURI, RFC 6733 section 4.3.1. The port is the default one of
+/// the scheme unless given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiameterUri<'a> {
+    pub secure: bool,
+    pub fqdn: &'a str,
+    pub port: u16,
+    pub transport: Option<&'a str>,
+    pub protocol: Option<&'a str>,
+}
+
+fn expect_len(buffer: &[u8], len: usize) -> Result<(), ParseError> {
+    if buffer.len() != len {
+        return Err(ParseErrorKind::InvalidAvpLength.into());
     }
+    Ok(())
+}
+
+pub fn parse_u32(buffer: &[u8]) -> Result<u32, ParseError> {
+    expect_len(buffer, 4)?;
     Ok(BigEndian::read_u32(buffer))
 }
+
+#[allow(dead_code)]
+pub fn parse_u64(buffer: &[u8]) -> Result<u64, ParseError> {
+    expect_len(buffer, 8)?;
+    Ok(u64::from_be_bytes(buffer.try_into().unwrap()))
+}
+
+#[allow(dead_code)]
+pub fn parse_i32(buffer: &[u8]) -> Result<i32, ParseError> {
+    expect_len(buffer, 4)?;
+    Ok(i32::from_be_bytes(buffer.try_into().unwrap()))
+}
+
+#[allow(dead_code)]
+pub fn parse_i64(buffer: &[u8]) -> Result<i64, ParseError> {
+    expect_len(buffer, 8)?;
+    Ok(i64::from_be_bytes(buffer.try_into().unwrap()))
+}
+
+#[allow(dead_code)]
+pub fn parse_f32(buffer: &[u8]) -> Result<f32, ParseError> {
+    expect_len(buffer, 4)?;
+    Ok(f32::from_be_bytes(buffer.try_into().unwrap()))
+}
+
+#[allow(dead_code)]
+pub fn parse_f64(buffer: &[u8]) -> Result<f64, ParseError> {
+    expect_len(buffer, 8)?;
+    Ok(f64::from_be_bytes(buffer.try_into().unwrap()))
+}
+
+/// Parses an Enumerated, which must be one of `values`.
+#[allow(dead_code)]
+pub fn parse_enumerated(buffer: &[u8], values: &[i32]) -> Result<i32, ParseError> {
+    let value = parse_i32(buffer)?;
+    if !values.contains(&value) {
+        return Err(ParseErrorKind::InvalidAvpValue.into());
+    }
+    Ok(value)
+}
+
+/// Parses a Time, seconds since 1900 in NTP format. Values with the most
+/// significant bit cleared are from after the rollover in 2036, as RFC 4330
+/// section 3 describes, and the ones with it set go back to 1968, before
+/// the Unix epoch.
+#[allow(dead_code)]
+pub fn parse_time(buffer: &[u8]) -> Result<SystemTime, ParseError> {
+    let ntp_seconds = parse_u32(buffer)? as u64;
+    let ntp_seconds = if ntp_seconds & 0x80000000 == 0 { ntp_seconds + (1 << 32) } else { ntp_seconds };
+    match ntp_seconds.checked_sub(NTP_UNIX_OFFSET) {
+        Some(seconds) => Ok(UNIX_EPOCH + Duration::from_secs(seconds)),
+        None => Ok(UNIX_EPOCH - Duration::from_secs(NTP_UNIX_OFFSET - ntp_seconds)),
+    }
+}
+
+#[allow(dead_code)]
+pub fn parse_address(buffer: &[u8]) -> Result<Address<'_>, ParseError> {
+    if buffer.len() < 2 {
+        return Err(ParseErrorKind::InvalidAvpLength.into());
+    }
+    let value = &buffer[2..];
+    match u16::from_be_bytes([buffer[0], buffer[1]]) {
+        address_family::IPV4 => {
+            expect_len(value, 4)?;
+            Ok(Address::Ip(IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3]))))
+        }
+        address_family::IPV6 => {
+            expect_len(value, 16)?;
+            let mut octets = [0; 16];
+            octets.copy_from_slice(value);
+            Ok(Address::Ip(IpAddr::V6(Ipv6Addr::from(octets))))
+        }
+        address_family::E164 => {
+            if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+                return Err(ParseErrorKind::InvalidAvpValue.into());
+            }
+            Ok(Address::E164(str::from_utf8(value).unwrap()))
+        }
+        _ => Err(ParseErrorKind::InvalidAvpValue.into()),
+    }
+}
+
+pub fn parse_utf8_string(buffer: &[u8]) -> Result<&str, ParseError> {
+    str::from_utf8(buffer).map_err(|_| ParseErrorKind::InvalidAvpValue.into())
+}
+
+/// Parses a DiameterIdentity, the FQDN of a node or the name of a realm.
+/// Underscores are let through, as they are common in names that are not
+/// in the DNS.
+#[allow(dead_code)]
+pub fn parse_diameter_identity(buffer: &[u8]) -> Result<&str, ParseError> {
+    if !is_diameter_identity(buffer) {
+        return Err(ParseErrorKind::InvalidAvpValue.into());
+    }
+    parse_utf8_string(buffer)
+}
+
+fn is_diameter_identity(value: &[u8]) -> bool {
+    !value.is_empty() && value.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_')
+}
+
+/// Parses a DiameterURI such as `aaas://host.example.com:5658;transport=tcp`.
+#[allow(dead_code)]
+pub fn parse_diameter_uri(buffer: &[u8]) -> Result<DiameterUri<'_>, ParseError> {
+    let invalid = || ParseError::from(ParseErrorKind::InvalidAvpValue);
+    let value = parse_utf8_string(buffer)?;
+    let (secure, rest) = if let Some(rest) = value.strip_prefix("aaas://") {
+        (true, rest)
+    } else if let Some(rest) = value.strip_prefix("aaa://") {
+        (false, rest)
+    } else {
+        return Err(invalid());
+    };
+    let mut parts = rest.split(';');
+    let authority = parts.next().unwrap();
+    let (fqdn, port) = match authority.split_once(':') {
+        Some((fqdn, port)) if !port.is_empty() && port.bytes().all(|c| c.is_ascii_digit()) => (fqdn, port.parse().map_err(|_| invalid())?),
+        Some(_) => return Err(invalid()),
+        None => (authority, if secure { 5658 } else { 3868 }),
+    };
+    if !is_diameter_identity(fqdn.as_bytes()) {
+        return Err(invalid());
+    }
+    let mut uri = DiameterUri { secure, fqdn, port, transport: None, protocol: None };
+    for parameter in parts {
+        match parameter.split_once('=') {
+            Some(("transport", transport)) if uri.transport.is_none() && ["tcp", "sctp", "udp"].contains(&transport) => uri.transport = Some(transport),
+            Some(("protocol", protocol)) if uri.protocol.is_none() && ["diameter", "radius", "tacacs+"].contains(&protocol) => uri.protocol = Some(protocol),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(uri)
+}
+
+#[test]
+pub fn errors_record_the_innermost_failed_avp() {
+    use super::{avps, avp_flags, MAX_FAILED_AVP_VALUE_LEN};
+    use super::message_builder::test_payload;
+    let payload = &test_payload(&|mb| {
+        mb.put_avp_u32(avps::VENDOR_ID, 1)
+            .begin_avp(avps::VENDOR_SPECIFIC_APPLICATION_ID)
+            .put_avp_bytes_with_flags(avps::PRODUCT_NAME, avp_flags::MANDATORY, &[b'x'; 200]);
+    });
+
+    let known_avps = [avps::VENDOR_ID, avps::VENDOR_SPECIFIC_APPLICATION_ID, avps::PRODUCT_NAME];
+    let reject_product_name = move |avp_id: AvpId, value: &[u8], _: &mut ()| -> Result<(), ParseError> {
+        match avp_id {
+            avps::VENDOR_SPECIFIC_APPLICATION_ID => parse_avps(value, &known_avps, false, &|avp_id: AvpId, _: &[u8], _: &mut ()| {
+                if avp_id == avps::PRODUCT_NAME { Err(ParseErrorKind::InvalidAvpValue.into()) } else { Ok(()) }
+            }, &mut ()),
+            _ => Ok(()),
+        }
+    };
+    let error = parse_avps(payload, &known_avps, false, &reject_product_name, &mut ()).unwrap_err();
+    assert_eq!(ParseErrorKind::InvalidAvpValue, error.kind);
+    let failed_avp = error.failed_avp.unwrap();
+    assert_eq!(avps::PRODUCT_NAME, failed_avp.avp_id);
+    assert_eq!(avp_flags::MANDATORY, failed_avp.flags);
+    assert_eq!(&[b'x'; MAX_FAILED_AVP_VALUE_LEN][..], &failed_avp.value[..]);
+
+    let ignore = |_: AvpId, _: &[u8], _: &mut ()| Ok(());
+    let error = parse_avps(&payload[..payload.len() - 4], &known_avps, false, &ignore, &mut ()).unwrap_err();
+    assert_eq!(ParseErrorKind::InvalidAvpLength, error.kind);
+    assert_eq!(avps::VENDOR_SPECIFIC_APPLICATION_ID, error.failed_avp.unwrap().avp_id);
+}
+
+#[test]
+pub fn rejects_unknown_mandatory_avps_unless_lenient() {
+    use super::avps;
+    use super::message_builder::test_payload;
+    let unknown = AvpId { code: 9999, vendor_id: 0 };
+    let payload = &test_payload(&|mb| {
+        mb.put_avp_u32(avps::VENDOR_ID, 1)
+            .put_avp_u32(unknown, 2)
+            .put_avp_bytes_with_flags(unknown, avp_flags::MANDATORY, &[0, 0, 0, 3]);
+    });
+    let count = |_: AvpId, _: &[u8], count: &mut u32| { *count += 1; Ok(()) };
+    let mut parsed = 0;
+    let error = parse_avps(payload, &[avps::VENDOR_ID], false, &count, &mut parsed).unwrap_err();
+    assert_eq!(ParseErrorKind::AvpUnsupported, error.kind);
+    assert_eq!(unknown, error.failed_avp.unwrap().avp_id);
+    parsed = 0;
+    parse_avps(payload, &[avps::VENDOR_ID], true, &count, &mut parsed).unwrap();
+    assert_eq!(1, parsed);
+}
+
+#[test]
+pub fn decodes_integer32() {
+    assert_eq!(Ok(-2), parse_i32(&[0xFF, 0xFF, 0xFF, 0xFE]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_i32(&[0, 0, 1]));
+}
+
+#[test]
+pub fn decodes_integer64() {
+    assert_eq!(Ok(-1), parse_i64(&[0xFF; 8]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_i64(&[0xFF; 4]));
+}
+
+#[test]
+pub fn decodes_unsigned64() {
+    assert_eq!(Ok(0x1_0000_0002), parse_u64(&[0, 0, 0, 1, 0, 0, 0, 2]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_u64(&[0; 9]));
+}
+
+#[test]
+pub fn decodes_float32() {
+    assert_eq!(Ok(1.5), parse_f32(&[0x3F, 0xC0, 0, 0]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_f32(&[0; 8]));
+}
+
+#[test]
+pub fn decodes_float64() {
+    assert_eq!(Ok(-2.0), parse_f64(&[0xC0, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_f64(&[0; 4]));
+}
+
+#[test]
+pub fn decodes_enumerated() {
+    assert_eq!(Ok(3), parse_enumerated(&[0, 0, 0, 3], &[1, 2, 3, 4]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_enumerated(&[0, 0, 0, 5], &[1, 2, 3, 4]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_enumerated(&[3], &[3]));
+}
+
+#[test]
+pub fn decodes_time_across_the_ntp_rollover() {
+    assert_eq!(Ok(UNIX_EPOCH), parse_time(&[0x83, 0xAA, 0x7E, 0x80]));
+    assert_eq!(Ok(UNIX_EPOCH + Duration::from_secs(2085978497)), parse_time(&[0, 0, 0, 1]));
+    assert_eq!(Ok(UNIX_EPOCH - Duration::from_secs(1)), parse_time(&[0x83, 0xAA, 0x7E, 0x7F]));
+    assert_eq!(Ok(UNIX_EPOCH - Duration::from_secs(NTP_UNIX_OFFSET - 0x80000000)), parse_time(&[0x80, 0, 0, 0]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_time(&[0; 5]));
+}
+
+#[test]
+pub fn decodes_addresses() {
+    assert_eq!(Ok(Address::Ip(IpAddr::from([10, 0, 0, 1]))), parse_address(&[0, 1, 10, 0, 0, 1]));
+    let mut ipv6 = vec![0, 2];
+    ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    assert_eq!(Ok(Address::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST))), parse_address(&ipv6));
+    assert_eq!(Ok(Address::E164("46701234567")), parse_address(b"\x00\x0846701234567"));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_address(&[0, 1, 10, 0, 0]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_address(&ipv6[..17]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpLength.into()), parse_address(&[0]));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_address(b"\x00\x08+4670"));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_address(&[0, 3, 1, 2, 3, 4]));
+}
+
+#[test]
+pub fn decodes_utf8_strings() {
+    assert_eq!(Ok("Grüße"), parse_utf8_string("Grüße".as_bytes()));
+    assert_eq!(Ok(""), parse_utf8_string(b""));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_utf8_string(&[b'a', 0xC3]));
+}
+
+#[test]
+pub fn decodes_diameter_identities() {
+    assert_eq!(Ok("ocs-1.example.com"), parse_diameter_identity(b"ocs-1.example.com"));
+    assert_eq!(Ok("dummy_realm"), parse_diameter_identity(b"dummy_realm"));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_diameter_identity(b""));
+    assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_diameter_identity(b"ocs example.com"));
+}
+
+#[test]
+pub fn decodes_diameter_uris() {
+    assert_eq!(Ok(DiameterUri { secure: false, fqdn: "ocs.example.com", port: 3868, transport: None, protocol: None }),
+               parse_diameter_uri(b"aaa://ocs.example.com"));
+    assert_eq!(Ok(DiameterUri { secure: true, fqdn: "ocs.example.com", port: 1812, transport: Some("udp"), protocol: Some("radius") }),
+               parse_diameter_uri(b"aaas://ocs.example.com:1812;transport=udp;protocol=radius"));
+    assert_eq!(5658, parse_diameter_uri(b"aaas://ocs.example.com;transport=tcp").unwrap().port);
+    for invalid in [&b"http://ocs.example.com"[..], b"aaa://", b"aaa://ocs.example.com:", b"aaa://ocs.example.com:70000",
+                    b"aaa://ocs.example.com;transport=quic", b"aaa://ocs.example.com;transport=tcp;transport=tcp"] {
+        assert_eq!(Err(ParseErrorKind::InvalidAvpValue.into()), parse_diameter_uri(invalid));
+    }
+}
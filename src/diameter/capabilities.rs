@@ -0,0 +1,204 @@
+use super::{avps, ParseError, ParseErrorKind};
+use super::avps::AvpId;
+use super::avp_parsers::{parse_avps, parse_u32};
+
+/// Advertised by relay agents, which take any application.
+pub const RELAY_APPLICATION_ID: u32 = 0xFFFFFFFF;
+
+/// The AVPs of a CER, RFC 6733 section 5.3.1.
+const CER_AVPS: &[AvpId] = &[
+    avps::ORIGIN_HOST, avps::ORIGIN_REALM, avps::HOST_IP_ADDRESS, avps::VENDOR_ID, avps::PRODUCT_NAME,
+    avps::ORIGIN_STATE_ID, avps::SUPPORTED_VENDOR_ID, avps::AUTH_APPLICATION_ID, avps::INBAND_SECURITY_ID,
+    avps::ACCT_APPLICATION_ID, avps::VENDOR_SPECIFIC_APPLICATION_ID, avps::FIRMWARE_REVISION,
+];
+
+const VENDOR_SPECIFIC_APPLICATION_ID_AVPS: &[AvpId] = &[avps::VENDOR_ID, avps::AUTH_APPLICATION_ID, avps::ACCT_APPLICATION_ID];
+
+pub mod inband_security {
+    pub const NO_INBAND_SECURITY: u32 = 0;
+    pub const TLS: u32 = 1;
+}
+
+/// The parts of a Capabilities-Exchange-Request that the server acts upon.
+pub struct CeRequest {
+    pub origin_host: Vec<u8>,
+    pub origin_realm: Vec<u8>,
+    pub product_name: Vec<u8>,
+    pub vendor_id: u32,
+    pub firmware_revision: Option<u32>,
+    pub origin_state_id: Option<u32>,
+    pub inband_security_ids: Vec<u32>,
+    pub supported_vendor_ids: Vec<u32>,
+    pub auth_application_ids: Vec<u32>,
+    pub acct_application_ids: Vec<u32>,
+    pub vendor_specific_application_ids: Vec<VendorSpecificApplicationId>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VendorSpecificApplicationId {
+    pub vendor_id: u32,
+    pub auth_application_id: Option<u32>,
+    pub acct_application_id: Option<u32>,
+}
+
+/// The applications both sides support, in the form the peer advertised
+/// them.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CommonApplications {
+    pub auth_application_ids: Vec<u32>,
+    pub acct_application_ids: Vec<u32>,
+    pub vendor_specific_application_ids: Vec<VendorSpecificApplicationId>,
+}
+
+impl CommonApplications {
+    pub fn is_empty(&self) -> bool {
+        self.auth_application_ids.is_empty() && self.acct_application_ids.is_empty() && self.vendor_specific_application_ids.is_empty()
+    }
+}
+
+impl CeRequest {
+    pub fn new() -> Self {
+        CeRequest {
+            origin_host: vec![],
+            origin_realm: vec![],
+            product_name: vec![],
+            vendor_id: 0,
+            firmware_revision: None,
+            origin_state_id: None,
+            inband_security_ids: vec![],
+            supported_vendor_ids: vec![],
+            auth_application_ids: vec![],
+            acct_application_ids: vec![],
+            vendor_specific_application_ids: vec![],
+        }
+    }
+
+    /// Parses a CER. Unknown AVPs with the M bit are refused unless
+    /// `lenient`.
+    pub fn parse(&mut self, buffer: &[u8], lenient: bool) -> Result<(), ParseError> {
+        self.origin_host.clear();
+        self.origin_realm.clear();
+        self.product_name.clear();
+        self.vendor_id = 0;
+        self.firmware_revision = None;
+        self.origin_state_id = None;
+        self.inband_security_ids.clear();
+        self.supported_vendor_ids.clear();
+        self.auth_application_ids.clear();
+        self.acct_application_ids.clear();
+        self.vendor_specific_application_ids.clear();
+        parse_avps(buffer, CER_AVPS, lenient, &move |avp_id, payload, result| parse_cer_avp(avp_id, payload, result, lenient), self)
+    }
+
+    /// True if the peer is willing to talk without in-band security, which
+    /// is implied when it does not send any Inband-Security-Id.
+    pub fn accepts_no_inband_security(&self) -> bool {
+        self.inband_security_ids.is_empty() || self.inband_security_ids.contains(&inband_security::NO_INBAND_SECURITY)
+    }
+
+    pub fn accepts_inband_tls(&self) -> bool {
+        self.inband_security_ids.contains(&inband_security::TLS)
+    }
+
+    /// Intersects the applications the peer advertised with the ones given.
+    /// A peer advertising the relay application gets all of them.
+    pub fn common_applications(&self, auth_application_ids: &[u32], acct_application_ids: &[u32]) -> CommonApplications {
+        if self.auth_application_ids.contains(&RELAY_APPLICATION_ID) || self.acct_application_ids.contains(&RELAY_APPLICATION_ID) {
+            return CommonApplications {
+                auth_application_ids: auth_application_ids.to_vec(),
+                acct_application_ids: acct_application_ids.to_vec(),
+                vendor_specific_application_ids: vec![],
+            };
+        }
+        let is_supported = |id: Option<u32>, supported: &[u32]| id.is_some_and(|id| supported.contains(&id));
+        CommonApplications {
+            auth_application_ids: intersection(&self.auth_application_ids, auth_application_ids),
+            acct_application_ids: intersection(&self.acct_application_ids, acct_application_ids),
+            vendor_specific_application_ids: self.vendor_specific_application_ids.iter()
+                .filter(|app| is_supported(app.auth_application_id, auth_application_ids) || is_supported(app.acct_application_id, acct_application_ids))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Intersects the vendors the peer supports, either in Supported-Vendor-Id
+    /// or by their applications, with the ones given.
+    pub fn common_vendor_ids(&self, vendor_ids: &[u32]) -> Vec<u32> {
+        vendor_ids.iter()
+            .filter(|id| self.supported_vendor_ids.contains(id) || self.vendor_specific_application_ids.iter().any(|app| app.vendor_id == **id))
+            .cloned()
+            .collect()
+    }
+}
+
+fn intersection(advertised: &[u32], supported: &[u32]) -> Vec<u32> {
+    let mut common: Vec<u32> = advertised.iter().filter(|id| supported.contains(id)).cloned().collect();
+    common.dedup();
+    common
+}
+
+fn parse_cer_avp(avp_id: AvpId, payload: &[u8], result: &mut CeRequest, lenient: bool) -> Result<(), ParseError> {
+    match avp_id {
+        avps::ORIGIN_HOST => {
+            if !result.origin_host.is_empty() {
+                return Err(ParseErrorKind::AvpOccursTooManyTimes.into());
+            }
+            result.origin_host.extend_from_slice(payload);
+        }
+        avps::ORIGIN_REALM => {
+            if !result.origin_realm.is_empty() {
+                return Err(ParseErrorKind::AvpOccursTooManyTimes.into());
+            }
+            result.origin_realm.extend_from_slice(payload);
+        }
+        avps::PRODUCT_NAME => {
+            result.product_name.clear();
+            result.product_name.extend_from_slice(payload);
+        }
+        avps::VENDOR_ID => result.vendor_id = parse_u32(payload)?,
+        avps::FIRMWARE_REVISION => result.firmware_revision = Some(parse_u32(payload)?),
+        avps::ORIGIN_STATE_ID => result.origin_state_id = Some(parse_u32(payload)?),
+        avps::INBAND_SECURITY_ID => result.inband_security_ids.push(parse_u32(payload)?),
+        avps::SUPPORTED_VENDOR_ID => result.supported_vendor_ids.push(parse_u32(payload)?),
+        avps::AUTH_APPLICATION_ID => result.auth_application_ids.push(parse_u32(payload)?),
+        avps::ACCT_APPLICATION_ID => result.acct_application_ids.push(parse_u32(payload)?),
+        avps::VENDOR_SPECIFIC_APPLICATION_ID => {
+            let mut app = VendorSpecificApplicationId { vendor_id: 0, auth_application_id: None, acct_application_id: None };
+            parse_avps(payload, VENDOR_SPECIFIC_APPLICATION_ID_AVPS, lenient, &parse_vendor_specific_application_avp, &mut app)?;
+            result.vendor_specific_application_ids.push(app);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_vendor_specific_application_avp(avp_id: AvpId, payload: &[u8], result: &mut VendorSpecificApplicationId) -> Result<(), ParseError> {
+    match avp_id {
+        avps::VENDOR_ID => result.vendor_id = parse_u32(payload)?,
+        avps::AUTH_APPLICATION_ID => result.auth_application_id = Some(parse_u32(payload)?),
+        avps::ACCT_APPLICATION_ID => result.acct_application_id = Some(parse_u32(payload)?),
+        _ => {}
+    }
+    Ok(())
+}
+
+#[test]
+pub fn negotiates_common_applications() {
+    use super::message_builder::test_payload;
+    let payload = test_payload(&|mb| {
+        mb.put_avp_u32(avps::AUTH_APPLICATION_ID, 16777238)
+            .put_avp_u32(avps::ACCT_APPLICATION_ID, 3);
+        mb.begin_avp(avps::VENDOR_SPECIFIC_APPLICATION_ID)
+            .put_avp_u32(avps::VENDOR_ID, 10415)
+            .put_avp_u32(avps::AUTH_APPLICATION_ID, 4);
+    });
+    let mut cer = CeRequest::new();
+    cer.parse(&payload, false).unwrap();
+
+    let common = cer.common_applications(&[4], &[]);
+    assert!(common.auth_application_ids.is_empty() && common.acct_application_ids.is_empty());
+    assert_eq!(vec![VendorSpecificApplicationId { vendor_id: 10415, auth_application_id: Some(4), acct_application_id: None }],
+               common.vendor_specific_application_ids);
+    assert_eq!(vec![10415], cer.common_vendor_ids(&[10415]));
+    assert!(cer.common_applications(&[5], &[]).is_empty());
+}
@@ -0,0 +1,38 @@
+use gy;
+use super::avps;
+use super::avps::AvpId;
+use super::avp_flags;
+use super::avp_flags::AvpFlags;
+
+/// The flags an AVP is sent with according to the specification defining
+/// it: V if it is vendor specific, and M unless its flag rules say the M bit
+/// must not or need not be set. Covers the AVPs of RFC 6733, RFC 4006 and
+/// the 3GPP ones used for Gy; others only get the V bit.
+pub fn avp_flags(avp_id: AvpId) -> AvpFlags {
+    let vendor = if avp_id.vendor_id != 0 { avp_flags::VENDOR } else { avp_flags::NONE };
+    if is_mandatory(avp_id) {
+        vendor | avp_flags::MANDATORY
+    } else {
+        vendor
+    }
+}
+
+fn is_mandatory(avp_id: AvpId) -> bool {
+    matches!(avp_id,
+        avps::SESSION_ID | avps::RESULT_CODE | avps::ORIGIN_HOST | avps::ORIGIN_REALM | avps::VENDOR_ID
+        | avps::HOST_IP_ADDRESS | avps::ORIGIN_STATE_ID | avps::DESTINATION_HOST | avps::DESTINATION_REALM
+        | avps::USER_NAME | avps::EVENT_TIMESTAMP | avps::ACCT_MULTI_SESSION_ID | avps::TERMINATION_CAUSE
+        | avps::PROXY_INFO | avps::PROXY_HOST | avps::PROXY_STATE | avps::ROUTE_RECORD | avps::SUPPORTED_VENDOR_ID | avps::AUTH_APPLICATION_ID
+        | avps::ACCT_APPLICATION_ID | avps::VENDOR_SPECIFIC_APPLICATION_ID | avps::INBAND_SECURITY_ID
+        | avps::DISCONNECT_CAUSE | avps::FAILED_AVP
+        | gy::avps::CC_REQUEST_NUMBER | gy::avps::CC_REQUEST_TYPE | gy::avps::CC_SESSION_FAILOVER
+        | gy::avps::CC_SUB_SESSION_ID | gy::avps::MULTIPLE_SERVICES_INDICATOR | gy::avps::MULTIPLE_SERVICES_CC
+        | gy::avps::REQUESTED_SERVICE_UNIT | gy::avps::GRANTED_SERVICE_UNIT | gy::avps::USED_SERVICE_UNIT
+        | gy::avps::SERVICE_IDENTIFIER | gy::avps::RATING_GROUP | gy::avps::VALIDITY_TIME | gy::avps::CC_INPUT_OCTETS
+        | gy::avps::CC_OUTPUT_OCTETS | gy::avps::CC_TOTAL_OCTETS | gy::avps::CC_TIME | gy::avps::FINAL_UNIT_INDICATION
+        | gy::avps::REQUESTED_ACTION | gy::avps::SUBSCRIPTION_ID | gy::avps::TARIFF_CHANGE_USAGE
+        | gy::avps::G_S_U_POOL_REFERENCE | gy::avps::SERVICE_CONTEXT_ID
+        | gy::avps::TIME_QUOTA_THRESHOLD | gy::avps::VOLUME_QUOTA_THRESHOLD | gy::avps::QUOTA_HOLDING_TIME
+        | gy::avps::REPORTING_REASON | gy::avps::SERVICE_INFORMATION | gy::avps::QUOTA_CONSUMPTION_TIME
+        | gy::avps::TRIGGER)
+}
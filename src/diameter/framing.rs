@@ -0,0 +1,174 @@
+use std::convert::TryFrom;
+use super::ParseError;
+use super::message_header::{MessageHeader, MESSAGE_HEADER_SIZE};
+
+/// Splits a byte stream into complete Diameter messages.
+///
+/// Data is read in large chunks into a single reusable buffer and as many
+/// complete messages as it holds are handed out as slices into it. A message
+/// that is only partially received stays in the buffer and is moved to the
+/// front once the free space at the end runs out.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    max_payload_len: u32,
+}
+
+pub struct Frame<'a> {
+    pub header: MessageHeader,
+    pub payload: &'a [u8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The header is invalid. It is read as well as possible, so that the
+    /// message can be answered before the connection is closed.
+    InvalidHeader(MessageHeader, ParseError),
+    TooLarge(u32),
+}
+
+impl FrameDecoder {
+    /// `max_payload_len` must leave room for a message header within `capacity`.
+    pub fn new(capacity: usize, max_payload_len: u32) -> Self {
+        assert!(max_payload_len as usize + MESSAGE_HEADER_SIZE as usize <= capacity);
+        FrameDecoder { buffer: vec![0u8; capacity], start: 0, end: 0, max_payload_len }
+    }
+
+    /// Returns the free space to read new data into. Must be followed by a
+    /// call to `commit` with the number of bytes actually stored.
+    pub fn read_space(&mut self) -> &mut [u8] {
+        if self.end == self.buffer.len() {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        &mut self.buffer[self.end..]
+    }
+
+    pub fn commit(&mut self, n: usize) {
+        self.end += n;
+        debug_assert!(self.end <= self.buffer.len());
+    }
+
+    /// True if nothing is buffered, not even part of a message.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Removes and returns what has been received after the last complete
+    /// message, for when the rest of the stream is no longer Diameter.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let data = self.buffer[self.start..self.end].to_vec();
+        self.start = 0;
+        self.end = 0;
+        data
+    }
+
+    /// Returns the next complete message, or `None` if more data is needed.
+    pub fn next_frame(&mut self) -> Result<Option<Frame<'_>>, FrameError> {
+        let available = self.end - self.start;
+        if available < MESSAGE_HEADER_SIZE as usize {
+            return Ok(None);
+        }
+        let header_end = self.start + MESSAGE_HEADER_SIZE as usize;
+        let header_bytes = <&[u8; MESSAGE_HEADER_SIZE as usize]>::try_from(&self.buffer[self.start..header_end]).unwrap();
+        let header = MessageHeader::parse(header_bytes)
+            .map_err(|e| FrameError::InvalidHeader(MessageHeader::read(header_bytes), e))?;
+        if header.payload_len() > self.max_payload_len {
+            return Err(FrameError::TooLarge(header.payload_len()));
+        }
+        if available < header.total_len() as usize {
+            return Ok(None);
+        }
+        let payload_end = header_end + header.payload_len() as usize;
+        self.start = payload_end;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        Ok(Some(Frame { header, payload: &self.buffer[header_end..payload_end] }))
+    }
+}
+
+#[cfg(test)]
+fn test_message(hop_by_hop: u32, payload_len: usize) -> Vec<u8> {
+    use super::message_header::{EndToEnd, HopByHop};
+    use super::message_builder::MessageBuilder;
+    let mut buffer = vec![];
+    MessageBuilder::new(&mut buffer, super::message_flags::REQUEST, super::commands::DEVICE_WATCHDOG, HopByHop(hop_by_hop), EndToEnd(0))
+        .put_avp_bytes(super::avps::ORIGIN_HOST, &vec![b'x'; payload_len - 8]);
+    buffer
+}
+
+#[cfg(test)]
+fn feed(decoder: &mut FrameDecoder, data: &[u8]) {
+    decoder.read_space()[0..data.len()].copy_from_slice(data);
+    decoder.commit(data.len());
+}
+
+#[test]
+pub fn splits_several_messages_from_one_read() {
+    let mut data = test_message(1, 12);
+    data.extend_from_slice(&test_message(2, 40));
+    data.extend_from_slice(&test_message(3, 8));
+    let mut decoder = FrameDecoder::new(1024, 512);
+    feed(&mut decoder, &data);
+    let mut ids = vec![];
+    while let Some(frame) = decoder.next_frame().unwrap() {
+        assert_eq!(frame.header.payload_len() as usize, frame.payload.len());
+        ids.push(frame.header.hop_by_hop.0);
+    }
+    assert_eq!(vec![1, 2, 3], ids);
+}
+
+#[test]
+pub fn carries_partial_message_over() {
+    let data = test_message(7, 100);
+    let mut decoder = FrameDecoder::new(128, 100);
+    feed(&mut decoder, &data[0..10]);
+    assert!(decoder.next_frame().unwrap().is_none());
+    feed(&mut decoder, &data[10..60]);
+    assert!(decoder.next_frame().unwrap().is_none());
+    feed(&mut decoder, &data[60..]);
+    assert_eq!(7, decoder.next_frame().unwrap().unwrap().header.hop_by_hop.0);
+}
+
+#[test]
+pub fn compacts_when_buffer_end_is_reached() {
+    let first = test_message(1, 60);
+    let second = test_message(2, 60);
+    let mut decoder = FrameDecoder::new(128, 100);
+    feed(&mut decoder, &first);
+    feed(&mut decoder, &second[0..48]);
+    assert_eq!(1, decoder.next_frame().unwrap().unwrap().header.hop_by_hop.0);
+    assert!(decoder.next_frame().unwrap().is_none());
+    assert_eq!(128 - 48, decoder.read_space().len());
+    feed(&mut decoder, &second[48..]);
+    assert_eq!(2, decoder.next_frame().unwrap().unwrap().header.hop_by_hop.0);
+}
+
+#[test]
+pub fn rejects_too_large_message_from_header() {
+    let data = test_message(1, 200);
+    let mut decoder = FrameDecoder::new(128, 100);
+    feed(&mut decoder, &data[0..20]);
+    assert_eq!(Some(FrameError::TooLarge(200)), decoder.next_frame().err());
+}
+
+#[test]
+pub fn recovers_identifiers_from_invalid_header() {
+    use super::ParseErrorKind;
+    let mut data = test_message(9, 12);
+    data[4] |= 0x01;
+    let mut decoder = FrameDecoder::new(128, 100);
+    feed(&mut decoder, &data);
+    match decoder.next_frame() {
+        Err(FrameError::InvalidHeader(header, e)) => {
+            assert_eq!(9, header.hop_by_hop.0);
+            assert_eq!(super::commands::DEVICE_WATCHDOG, header.command_id);
+            assert_eq!(ParseErrorKind::InvalidBitInHeader, e.kind);
+        }
+        _ => panic!("expected an invalid header"),
+    }
+}
@@ -2,11 +2,15 @@ extern crate byteorder;
 
 use byteorder::{ByteOrder, BigEndian};
 use std::net::IpAddr;
+use super::avps;
 use super::avps::AvpId;
+use super::avp_flags;
 use super::avp_flags::AvpFlags;
+use super::avp_iter::AvpIter;
+use super::dictionary;
 use super::commands::CommandId;
 use super::message_flags::MessageFlags;
-use super::message_header::{EndToEnd, HopByHop};
+use super::message_header::{EndToEnd, HopByHop, MESSAGE_HEADER_SIZE};
 
 pub struct MessageBuilder<'a> {
     buffer: &'a mut Vec<u8>,
@@ -27,76 +31,82 @@ impl<'a> MessageBuilder<'a> {
         write_u32(buffer, start_pos + 8, cmd.application_id);
         write_u32(buffer, start_pos + 12, hop_by_hop.0);
         write_u32(buffer, start_pos + 16, end_to_end.0);
-        MessageBuilder { buffer: buffer, start_pos: start_pos, is_message: true }
+        MessageBuilder { buffer, start_pos, is_message: true }
     }
 
-    pub fn put_avp_empty<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags) -> &'b mut MessageBuilder<'a> {
-        self.write_header(avp_id, flags, 0);
+    /// Returns a builder to append more AVPs to the message at `start_pos`,
+    /// which must be the last one in `buffer`.
+    pub fn reopen(buffer: &'a mut Vec<u8>, start_pos: usize) -> Self {
+        MessageBuilder { buffer, start_pos, is_message: true }
+    }
+
+    pub fn put_avp_empty<'b>(&'b mut self, avp_id: AvpId) -> &'b mut MessageBuilder<'a> {
+        self.write_header(avp_id, 0);
         self
     }
 
-    pub fn put_avp_u32<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: u32) -> &'b mut MessageBuilder<'a> {
-        self.write_header(avp_id, flags, 4);
+    pub fn put_avp_u32<'b>(&'b mut self, avp_id: AvpId, value: u32) -> &'b mut MessageBuilder<'a> {
+        self.write_header(avp_id, 4);
         let pos = self.buffer.len();
         extend(self.buffer, 4);
         write_u32(self.buffer, pos, value);
         self
     }
 
-    pub fn put_avp_u32_option<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: Option<u32>) -> &'b mut MessageBuilder<'a> {
+    pub fn put_avp_u32_option<'b>(&'b mut self, avp_id: AvpId, value: Option<u32>) -> &'b mut MessageBuilder<'a> {
         if let Some(v) = value {
-            self.put_avp_u32(avp_id, flags, v);
+            self.put_avp_u32(avp_id, v);
         }
         self
     }
 
-    pub fn put_avp_u32_nonzero<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: u32) -> &'b mut MessageBuilder<'a> {
+    pub fn put_avp_u32_nonzero<'b>(&'b mut self, avp_id: AvpId, value: u32) -> &'b mut MessageBuilder<'a> {
         if value > 0 {
-            self.put_avp_u32(avp_id, flags, value);
+            self.put_avp_u32(avp_id, value);
         }
         self
     }
 
-    pub fn put_avp_u64<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: u64) -> &'b mut MessageBuilder<'a> {
-        self.write_header(avp_id, flags, 8);
+    pub fn put_avp_u64<'b>(&'b mut self, avp_id: AvpId, value: u64) -> &'b mut MessageBuilder<'a> {
+        self.write_header(avp_id, 8);
         let pos = self.buffer.len();
         extend(self.buffer, 8);
         write_u64(self.buffer, pos, value);
         self
     }
 
-    pub fn put_avp_u64_nonzero<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: u64) -> &'b mut MessageBuilder<'a> {
+    pub fn put_avp_u64_nonzero<'b>(&'b mut self, avp_id: AvpId, value: u64) -> &'b mut MessageBuilder<'a> {
         if value > 0 {
-            self.put_avp_u64(avp_id, flags, value);
+            self.put_avp_u64(avp_id, value);
         }
         self
     }
 
-    pub fn put_avp_bytes<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: &[u8]) -> &'b mut MessageBuilder<'a> {
-        self.write_header(avp_id, flags, value.len() as u32);
+    pub fn put_avp_bytes<'b>(&'b mut self, avp_id: AvpId, value: &[u8]) -> &'b mut MessageBuilder<'a> {
+        self.write_header(avp_id, value.len() as u32);
         self.buffer.extend_from_slice(value);
         self.write_padding();
         self
     }
 
-    pub fn put_avp_bytes_nonempty<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: &[u8]) -> &'b mut MessageBuilder<'a> {
+    pub fn put_avp_bytes_nonempty<'b>(&'b mut self, avp_id: AvpId, value: &[u8]) -> &'b mut MessageBuilder<'a> {
         if !value.is_empty() {
-            self.put_avp_bytes(avp_id, flags, value);
+            self.put_avp_bytes(avp_id, value);
         }
         self
     }
 
-    pub fn put_avp_address<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, address: IpAddr) -> &'b mut MessageBuilder<'a> {
+    pub fn put_avp_address<'b>(&'b mut self, avp_id: AvpId, address: IpAddr) -> &'b mut MessageBuilder<'a> {
         match address {
             IpAddr::V4(a) => {
-                self.write_header(avp_id, flags, 2 + 4);
+                self.write_header(avp_id, 2 + 4);
                 let pos = self.buffer.len();
                 extend(self.buffer, 2);
                 write_u16(self.buffer, pos, 1); // https://www.iana.org/assignments/address-family-numbers/address-family-numbers.xhtml
                 self.buffer.extend_from_slice(&a.octets());
             }
             IpAddr::V6(a) => {
-                self.write_header(avp_id, flags, 2 + 16);
+                self.write_header(avp_id, 2 + 16);
                 let pos = self.buffer.len();
                 extend(self.buffer, 2);
                 write_u16(self.buffer, pos, 2);
@@ -107,13 +117,35 @@ impl<'a> MessageBuilder<'a> {
         self
     }
 
-    pub fn begin_avp<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags) -> MessageBuilder<'b> {
+    /// Like `put_avp_bytes`, but with the given flags instead of the ones
+    /// from the dictionary, such as when echoing a received AVP.
+    pub fn put_avp_bytes_with_flags<'b>(&'b mut self, avp_id: AvpId, flags: AvpFlags, value: &[u8]) -> &'b mut MessageBuilder<'a> {
+        self.write_header_with_flags(avp_id, flags, value.len() as u32);
+        self.buffer.extend_from_slice(value);
+        self.write_padding();
+        self
+    }
+
+    /// Appends already encoded AVPs as they are.
+    pub fn put_raw<'b>(&'b mut self, avps: &[u8]) -> &'b mut MessageBuilder<'a> {
+        self.buffer.extend_from_slice(avps);
+        self
+    }
+
+    pub fn begin_avp<'b>(&'b mut self, avp_id: AvpId) -> MessageBuilder<'b> {
         let start_pos = self.buffer.len();
-        self.write_header(avp_id, flags, 0);
-        MessageBuilder { buffer: self.buffer, start_pos: start_pos, is_message: false }
+        self.write_header(avp_id, 0);
+        MessageBuilder { buffer: self.buffer, start_pos, is_message: false }
+    }
+
+    fn write_header(&mut self, avp_id: AvpId, payload_length: u32) {
+        self.write_header_with_flags(avp_id, dictionary::avp_flags(avp_id), payload_length);
     }
 
-    fn write_header(&mut self, avp_id: AvpId, flags: AvpFlags, payload_length: u32) {
+    /// The V bit is set or cleared to match the vendor id whatever `flags`
+    /// says, so that the header stays consistent.
+    fn write_header_with_flags(&mut self, avp_id: AvpId, flags: AvpFlags, payload_length: u32) {
+        let flags = if avp_id.vendor_id != 0 { flags | avp_flags::VENDOR } else { flags - avp_flags::VENDOR };
         let pos = self.buffer.len();
         if avp_id.vendor_id != 0 {
             extend(self.buffer, VENDOR_HEADER_SIZE as usize);
@@ -149,23 +181,104 @@ impl<'a> Drop for MessageBuilder<'a> {
     }
 }
 
+/// A message encoded once up front. Writing it out only copies the bytes and
+/// fills in the identifiers of the request being answered.
+pub struct MessageTemplate {
+    buffer: Vec<u8>,
+}
+
+impl MessageTemplate {
+    pub fn new<F>(flags: MessageFlags, cmd: CommandId, build: F) -> Self where F: FnOnce(&mut MessageBuilder) {
+        let mut buffer = vec![];
+        build(&mut MessageBuilder::new(&mut buffer, flags, cmd, HopByHop(0), EndToEnd(0)));
+        MessageTemplate { buffer }
+    }
+
+    /// Makes a template of a message encoded already, such as an answer
+    /// that is to be sent again.
+    pub fn from_message(message: &[u8]) -> Self {
+        MessageTemplate { buffer: message.to_vec() }
+    }
+
+    /// Appends the message to `output` and returns the position it starts at.
+    pub fn write(&self, output: &mut Vec<u8>, hop_by_hop: HopByHop, end_to_end: EndToEnd) -> usize {
+        let start = output.len();
+        output.extend_from_slice(&self.buffer);
+        write_ids(output, start, hop_by_hop, end_to_end);
+        start
+    }
+
+    /// Like `write`, but returns a builder to append more AVPs to the message
+    /// with. The message length is updated when the builder is dropped.
+    pub fn write_extended<'a>(&self, output: &'a mut Vec<u8>, hop_by_hop: HopByHop, end_to_end: EndToEnd) -> MessageBuilder<'a> {
+        let start = self.write(output, hop_by_hop, end_to_end);
+        MessageBuilder { buffer: output, start_pos: start, is_message: true }
+    }
+
+    /// Like `write_extended`, but with a Session-Id AVP inserted first as
+    /// session based answers such as the CCA require, unless it is empty,
+    /// and with the flags given, as these follow the request.
+    pub fn write_with_session_id<'a>(&self, output: &'a mut Vec<u8>, flags: MessageFlags, hop_by_hop: HopByHop, end_to_end: EndToEnd, session_id: &[u8]) -> MessageBuilder<'a> {
+        let start = output.len();
+        let header_size = MESSAGE_HEADER_SIZE as usize;
+        output.extend_from_slice(&self.buffer[0..header_size]);
+        output[start + 4] = flags.bits();
+        write_ids(output, start, hop_by_hop, end_to_end);
+        let mut mb = MessageBuilder { buffer: output, start_pos: start, is_message: true };
+        mb.put_avp_bytes_nonempty(avps::SESSION_ID, session_id)
+            .put_raw(&self.buffer[header_size..]);
+        mb
+    }
+}
+
+/// Overwrites the value of a top level Unsigned32 AVP, such as
+/// CC-Request-Number, in an encoded message. Returns false if the message
+/// does not contain the AVP.
+pub fn patch_avp_u32(message: &mut [u8], avp_id: AvpId, value: u32) -> bool {
+    let header_size = MESSAGE_HEADER_SIZE as usize;
+    let value_pos = AvpIter::new(&message[header_size..]).with_offsets()
+        .find(|(_, header, value)| header.avp_id == avp_id && value.len() == 4)
+        .map(|(start, header, _)| header_size + start + header.header_len());
+    match value_pos {
+        Some(pos) => {
+            BigEndian::write_u32(&mut message[pos..pos + 4], value);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Encodes a request with the AVPs that `build` adds and returns its
+/// payload, for tests of the parsers.
+#[cfg(test)]
+pub fn test_payload(build: &dyn Fn(&mut MessageBuilder)) -> Vec<u8> {
+    let mut message = vec![];
+    build(&mut MessageBuilder::new(&mut message, super::message_flags::REQUEST, super::commands::CAPABILITIES_EXCHANGE, HopByHop(1), EndToEnd(1)));
+    message.split_off(MESSAGE_HEADER_SIZE as usize)
+}
+
+fn write_ids(dst: &mut [u8], start: usize, hop_by_hop: HopByHop, end_to_end: EndToEnd) {
+    write_u32(dst, start + 12, hop_by_hop.0);
+    write_u32(dst, start + 16, end_to_end.0);
+}
+
 fn extend(vec: &mut Vec<u8>, n: usize) {
     let new_size = vec.len() + n;
     vec.resize(new_size, 0);
 }
 
 #[inline]
-fn write_u16(dst: &mut Vec<u8>, pos: usize, value: u16) {
+fn write_u16(dst: &mut [u8], pos: usize, value: u16) {
     BigEndian::write_u16(&mut dst[pos..pos + 2], value);
 }
 
 #[inline]
-fn write_u32(dst: &mut Vec<u8>, pos: usize, value: u32) {
+fn write_u32(dst: &mut [u8], pos: usize, value: u32) {
     BigEndian::write_u32(&mut dst[pos..pos + 4], value);
 }
 
 #[inline]
-fn write_u64(dst: &mut Vec<u8>, pos: usize, value: u64) {
+fn write_u64(dst: &mut [u8], pos: usize, value: u64) {
     BigEndian::write_u64(&mut dst[pos..pos + 8], value);
 }
 
@@ -182,7 +295,7 @@ pub fn testme() {
     let mut bb = vec![0u8; 0];
     {
         let mut mb = MessageBuilder::new(&mut bb, super::message_flags::NONE, super::commands::CAPABILITIES_EXCHANGE, HopByHop(0), EndToEnd(0));
-        mb.put_avp_u32(super::avps::ORIGIN_HOST, super::avp_flags::NONE, 50);
+        mb.put_avp_u32(super::avps::ORIGIN_HOST, 50);
     }
     assert_eq!(20 + 12, bb.len());
 }
@@ -191,7 +304,60 @@ pub fn testme() {
 pub fn testme2() {
     let mut bb = vec![0u8; 0];
     MessageBuilder::new(&mut bb, super::message_flags::NONE, super::commands::CAPABILITIES_EXCHANGE, HopByHop(0), EndToEnd(0))
-        .put_avp_u32(super::avps::ORIGIN_HOST, super::avp_flags::NONE, 50)
-        .put_avp_u32(super::avps::ORIGIN_HOST, super::avp_flags::NONE, 50);
+        .put_avp_u32(super::avps::ORIGIN_HOST, 50)
+        .put_avp_u32(super::avps::ORIGIN_HOST, 50);
     assert_eq!(20 + 2*12, bb.len());
 }
+
+#[test]
+pub fn template_patches_identifiers() {
+    let template = MessageTemplate::new(super::message_flags::NONE, super::commands::DEVICE_WATCHDOG, |mb| {
+        mb.put_avp_u32(super::avps::RESULT_CODE, 2001);
+    });
+    let mut expected = vec![];
+    MessageBuilder::new(&mut expected, super::message_flags::NONE, super::commands::DEVICE_WATCHDOG, HopByHop(7), EndToEnd(8))
+        .put_avp_u32(super::avps::RESULT_CODE, 2001);
+    let mut bb = vec![1u8, 2, 3];
+    assert_eq!(3, template.write(&mut bb, HopByHop(7), EndToEnd(8)));
+    assert_eq!(&expected[..], &bb[3..]);
+}
+
+#[test]
+pub fn template_with_session_id() {
+    let cmd = super::commands::CommandId { code: 272, application_id: 4 };
+    let request_number = super::avps::AvpId { code: 415, vendor_id: 0 };
+    let template = MessageTemplate::new(super::message_flags::NONE, cmd, |mb| {
+        mb.put_avp_u32(super::avps::RESULT_CODE, 2001)
+            .put_avp_u32(request_number, 0);
+    });
+    let mut expected = vec![];
+    MessageBuilder::new(&mut expected, super::message_flags::PROXIABLE, cmd, HopByHop(1), EndToEnd(2))
+        .put_avp_bytes(super::avps::SESSION_ID, b"host;1;2")
+        .put_avp_u32(super::avps::RESULT_CODE, 2001)
+        .put_avp_u32(request_number, 5)
+        .put_avp_u32(super::avps::VENDOR_ID, 1);
+    let mut bb = vec![];
+    template.write_with_session_id(&mut bb, super::message_flags::PROXIABLE, HopByHop(1), EndToEnd(2), b"host;1;2")
+        .put_avp_u32(super::avps::VENDOR_ID, 1);
+    assert!(patch_avp_u32(&mut bb, request_number, 5));
+    assert!(!patch_avp_u32(&mut bb, super::avps::PRODUCT_NAME, 5));
+    assert_eq!(expected, bb);
+}
+
+#[test]
+pub fn flags_come_from_the_dictionary() {
+    use super::avp_flags::{MANDATORY, VENDOR};
+    let threshold = AvpId { code: 868, vendor_id: 10415 };
+    let mut bb = vec![];
+    MessageBuilder::new(&mut bb, super::message_flags::NONE, super::commands::CAPABILITIES_EXCHANGE, HopByHop(0), EndToEnd(0))
+        .put_avp_u32(super::avps::ORIGIN_STATE_ID, 1)
+        .put_avp_bytes(super::avps::PRODUCT_NAME, b"x")
+        .put_avp_u32(threshold, 1)
+        .put_avp_bytes_with_flags(super::avps::VENDOR_ID, VENDOR, &[0, 0, 0, 1]);
+    let flags_at = |pos: usize| AvpFlags::from_bits(bb[pos + 4]).unwrap();
+    assert_eq!(MANDATORY, flags_at(20));
+    assert_eq!(avp_flags::NONE, flags_at(32));
+    assert_eq!(VENDOR | MANDATORY, flags_at(44));
+    assert_eq!(avp_flags::NONE, flags_at(60));
+    assert_eq!(72, bb.len());
+}
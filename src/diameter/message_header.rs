@@ -1,9 +1,10 @@
 use byteorder::{ByteOrder, BigEndian};
-use super::ParseError;
+use super::{ParseError, ParseErrorKind};
 use super::commands::CommandId;
 use super::message_flags::MessageFlags;
 
 pub const MESSAGE_HEADER_SIZE: u32 = 20;
+pub const PROTOCOL_VERSION: u8 = 1;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct HopByHop(pub u32);
@@ -11,6 +12,30 @@ pub struct HopByHop(pub u32);
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct EndToEnd(pub u32);
 
+/// Allocates the identifiers of requests that the server sends itself.
+/// Hop-by-hop identifiers start at a random value and only need to be unique
+/// on the connection. End-to-end identifiers keep the low 12 bits of the
+/// start time in their high bits, as RFC 6733 recommends, so that they stay
+/// unique across restarts.
+pub struct RequestIds {
+    hop_by_hop: u32,
+    end_to_end: u32,
+}
+
+impl RequestIds {
+    pub fn new(seed: u32, start_time: u64) -> Self {
+        RequestIds { hop_by_hop: seed, end_to_end: ((start_time as u32 & 0xFFF) << 20) | (seed & 0xFFFFF) }
+    }
+
+    pub fn next(&mut self) -> (HopByHop, EndToEnd) {
+        let ids = (HopByHop(self.hop_by_hop), EndToEnd(self.end_to_end));
+        self.hop_by_hop = self.hop_by_hop.wrapping_add(1);
+        self.end_to_end = (self.end_to_end & 0xFFF00000) | (self.end_to_end.wrapping_add(1) & 0xFFFFF);
+        ids
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MessageHeader {
     pub command_id: CommandId,
     pub flags: MessageFlags,
@@ -21,25 +46,35 @@ pub struct MessageHeader {
 
 impl MessageHeader {
     pub fn parse(buffer: &[u8; MESSAGE_HEADER_SIZE as usize]) -> Result<MessageHeader, ParseError> {
-        let length = BigEndian::read_u32(&buffer[0..4]) & 0x00FFFFFF;
-        if length < MESSAGE_HEADER_SIZE {
-            return Err(ParseError::InvalidMessageLength);
+        if buffer[0] != PROTOCOL_VERSION {
+            return Err(ParseErrorKind::UnsupportedVersion.into());
         }
+        let header = MessageHeader::read(buffer);
+        if header.length < MESSAGE_HEADER_SIZE {
+            return Err(ParseErrorKind::InvalidMessageLength.into());
+        }
+        if MessageFlags::from_bits(buffer[4]).is_none() {
+            return Err(ParseErrorKind::InvalidBitInHeader.into());
+        }
+        Ok(header)
+    }
+
+    /// Reads the header without checking it, dropping any reserved flags.
+    /// Enough to answer a message whose header `parse` refuses.
+    pub fn read(buffer: &[u8; MESSAGE_HEADER_SIZE as usize]) -> MessageHeader {
         let flags_and_code = BigEndian::read_u32(&buffer[4..8]);
-        let flags = MessageFlags::from_bits((flags_and_code >> 24) as u8).ok_or(ParseError::InvalidBitInHeader)?;
-        Ok(MessageHeader {
+        MessageHeader {
             command_id: CommandId {
                 code: flags_and_code & 0x00FFFFFF,
                 application_id: BigEndian::read_u32(&buffer[8..12]),
             },
-            length: length,
-            flags: flags,
+            length: BigEndian::read_u32(&buffer[0..4]) & 0x00FFFFFF,
+            flags: MessageFlags::from_bits_truncate((flags_and_code >> 24) as u8),
             hop_by_hop: HopByHop(BigEndian::read_u32(&buffer[12..16])),
             end_to_end: EndToEnd(BigEndian::read_u32(&buffer[16..20]))
-        })
+        }
     }
 
-    #[allow(dead_code)]
     pub fn total_len(&self) -> u32 {
         self.length
     }
@@ -48,3 +83,10 @@ impl MessageHeader {
         self.length - MESSAGE_HEADER_SIZE
     }
 }
+
+#[test]
+pub fn request_ids_keep_start_time_bits() {
+    let mut ids = RequestIds::new(0xFFFFFFFF, 0x12345);
+    assert_eq!((HopByHop(0xFFFFFFFF), EndToEnd(0x345FFFFF)), ids.next());
+    assert_eq!((HopByHop(0), EndToEnd(0x34500000)), ids.next());
+}
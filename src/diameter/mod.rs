@@ -32,21 +32,40 @@ pub mod avps {
     }
 
     define_constants!(
-        AvpId                    code, vendor_id;
-        SESSION_ID                263,         0;
-        RESULT_CODE               268,         0;
-        ORIGIN_HOST               264,         0;
-        ORIGIN_REALM              296,         0;
-        VENDOR_ID                 266,         0;
-        PRODUCT_NAME              269,         0;
-        FIRMWARE_REVISION         267,         0;
-        HOST_IP_ADDRESS           257,         0;
-        SUPPORTED_VENDOR_ID       265,         0;
-        AUTH_APPLICATION_ID       258,         0;
+        AvpId                          code, vendor_id;
+        SESSION_ID                      263,         0;
+        RESULT_CODE                     268,         0;
+        ORIGIN_HOST                     264,         0;
+        ORIGIN_REALM                    296,         0;
+        VENDOR_ID                       266,         0;
+        PRODUCT_NAME                    269,         0;
+        FIRMWARE_REVISION               267,         0;
+        HOST_IP_ADDRESS                 257,         0;
+        ORIGIN_STATE_ID                 278,         0;
+        DESTINATION_HOST                293,         0;
+        DESTINATION_REALM               283,         0;
+        USER_NAME                         1,         0;
+        EVENT_TIMESTAMP                  55,         0;
+        ACCT_MULTI_SESSION_ID            50,         0;
+        TERMINATION_CAUSE               295,         0;
+        PROXY_INFO                      284,         0;
+        ROUTE_RECORD                    282,         0;
+        PROXY_HOST                      280,         0;
+        PROXY_STATE                      33,         0;
+        SUPPORTED_VENDOR_ID             265,         0;
+        AUTH_APPLICATION_ID             258,         0;
+        ACCT_APPLICATION_ID             259,         0;
+        VENDOR_SPECIFIC_APPLICATION_ID  260,         0;
+        INBAND_SECURITY_ID              299,         0;
+        DISCONNECT_CAUSE                273,         0;
+        FAILED_AVP                      279,         0;
+        ERROR_MESSAGE                   281,         0;
     );
 }
 
 pub mod avp_flags {
+    #![allow(deprecated)]
+
     bitflags! {
         pub flags AvpFlags: u8 {
             const VENDOR    = 0x80,
@@ -59,6 +78,8 @@ pub mod avp_flags {
 }
 
 pub mod message_flags {
+    #![allow(deprecated)]
+
     bitflags! {
         pub flags MessageFlags: u8 {
             const REQUEST       = 0x80,
@@ -74,44 +95,120 @@ pub mod message_flags {
 pub mod result_codes {
     pub const SUCCESS: u32 = 2001;
     pub const COMMAND_UNSUPPORTED: u32 = 3001;
+    pub const UNABLE_TO_DELIVER: u32 = 3002;
+    pub const REALM_NOT_SERVED: u32 = 3003;
+    pub const LOOP_DETECTED: u32 = 3005;
     pub const APPLICATION_UNSUPPORTED: u32 = 3007;
+    pub const INVALID_HDR_BITS: u32 = 3008;
+    pub const UNKNOWN_PEER: u32 = 3010;
+    pub const ELECTION_LOST: u32 = 4003;
+    pub const NO_COMMON_APPLICATION: u32 = 5010;
+    pub const NO_COMMON_SECURITY: u32 = 5017;
+
+    /// Protocol errors are answered with the E bit set, in the generic
+    /// answer-message format rather than the one of the command.
+    pub fn is_protocol_error(result_code: u32) -> bool {
+        (3000..4000).contains(&result_code)
+    }
+}
+
+pub mod disconnect_cause {
+    pub const REBOOTING: u32 = 0;
+    pub const DO_NOT_WANT_TO_TALK_TO_YOU: u32 = 2;
 }
 
 pub mod avp_header;
+pub mod avp_iter;
 pub mod avp_parsers;
+pub mod capabilities;
+pub mod dictionary;
+pub mod framing;
 pub mod message_builder;
 pub mod message_header;
+pub mod routing;
+
+/// Values of a Failed-AVP longer than this are cut short.
+pub const MAX_FAILED_AVP_VALUE_LEN: usize = 128;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum ParseError {
+pub enum ParseErrorKind {
+    UnsupportedVersion,
     InvalidMessageLength,
     InvalidBitInHeader,
     InvalidAvpLength,
     InvalidAvpValue,
     InvalidAvpBits,
     AvpOccursTooManyTimes,
+    AvpUnsupported,
 }
 
-impl ParseError {
-    pub fn description(&self) -> &str {
+/// What is wrong with a received message, and a copy of the AVP it was
+/// found in if there is one, for the Failed-AVP of the answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub failed_avp: Option<FailedAvp>,
+}
+
+/// An offending AVP as received, with its value truncated to
+/// `MAX_FAILED_AVP_VALUE_LEN` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedAvp {
+    pub avp_id: avps::AvpId,
+    pub flags: avp_flags::AvpFlags,
+    pub value: Vec<u8>,
+}
+
+impl ParseErrorKind {
+    pub fn description(&self) -> &'static str {
         match *self {
-            ParseError::InvalidMessageLength => "invalid message length",
-            ParseError::InvalidBitInHeader => "invalid bit in message header",
-            ParseError::InvalidAvpLength => "invalid AVP length",
-            ParseError::InvalidAvpValue => "invalid AVP value",
-            ParseError::InvalidAvpBits => "invalid bits in AVP header",
-            ParseError::AvpOccursTooManyTimes => "AVP occurs too many times",
+            ParseErrorKind::UnsupportedVersion => "unsupported protocol version",
+            ParseErrorKind::InvalidMessageLength => "invalid message length",
+            ParseErrorKind::InvalidBitInHeader => "invalid bit in message header",
+            ParseErrorKind::InvalidAvpLength => "invalid AVP length",
+            ParseErrorKind::InvalidAvpValue => "invalid AVP value",
+            ParseErrorKind::InvalidAvpBits => "invalid bits in AVP header",
+            ParseErrorKind::AvpOccursTooManyTimes => "AVP occurs too many times",
+            ParseErrorKind::AvpUnsupported => "AVP with the M bit is not supported",
         }
     }
 
     pub fn result_code(&self) -> u32 {
         match *self {
-            ParseError::InvalidMessageLength => 5015,
-            ParseError::InvalidBitInHeader => 5013,
-            ParseError::InvalidAvpLength => 5014,
-            ParseError::InvalidAvpValue => 5004,
-            ParseError::InvalidAvpBits => 3009, // TODO: Error bit should be set in replies
-            ParseError::AvpOccursTooManyTimes => 5009
+            ParseErrorKind::UnsupportedVersion => 5011,
+            ParseErrorKind::InvalidMessageLength => 5015,
+            ParseErrorKind::InvalidBitInHeader => 5013,
+            ParseErrorKind::InvalidAvpLength => 5014,
+            ParseErrorKind::InvalidAvpValue => 5004,
+            ParseErrorKind::InvalidAvpBits => 3009,
+            ParseErrorKind::AvpOccursTooManyTimes => 5009,
+            ParseErrorKind::AvpUnsupported => 5001,
+        }
+    }
+}
+
+impl ParseError {
+    pub fn description(&self) -> &'static str {
+        self.kind.description()
+    }
+
+    pub fn result_code(&self) -> u32 {
+        self.kind.result_code()
+    }
+
+    /// Records the AVP the error was found in, unless an AVP nested in it
+    /// was recorded already.
+    pub fn in_avp(mut self, header: &avp_header::AvpHeader, value: &[u8]) -> Self {
+        if self.failed_avp.is_none() {
+            let len = value.len().min(MAX_FAILED_AVP_VALUE_LEN);
+            self.failed_avp = Some(FailedAvp { avp_id: header.avp_id, flags: header.flags, value: value[..len].to_vec() });
         }
+        self
+    }
+}
+
+impl From<ParseErrorKind> for ParseError {
+    fn from(kind: ParseErrorKind) -> Self {
+        ParseError { kind, failed_avp: None }
     }
 }
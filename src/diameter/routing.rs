@@ -0,0 +1,62 @@
+use super::avps;
+use super::avp_iter::AvpIter;
+use super::avp_parsers::round_up;
+
+/// The AVPs of a request that relay and proxy agents add on its way, RFC
+/// 6733 section 6.1.
+pub struct RoutingAvps {
+    /// The Proxy-Info AVPs, encoded as received, in order.
+    pub proxy_infos: Vec<u8>,
+    route_records: Vec<Vec<u8>>,
+}
+
+impl RoutingAvps {
+    pub fn new() -> Self {
+        RoutingAvps { proxy_infos: vec![], route_records: vec![] }
+    }
+
+    /// Collects the routing AVPs among the top level AVPs of a request.
+    /// Stops at the first malformed AVP, which is left to the parser of the
+    /// command to report.
+    pub fn parse(&mut self, buffer: &[u8]) {
+        self.proxy_infos.clear();
+        self.route_records.clear();
+        for (start, header, value) in AvpIter::new(buffer).with_offsets() {
+            match header.avp_id {
+                avps::PROXY_INFO => {
+                    self.proxy_infos.extend_from_slice(&buffer[start..start + header.total_len()]);
+                    self.proxy_infos.resize(round_up(self.proxy_infos.len()), 0);
+                }
+                avps::ROUTE_RECORD => self.route_records.push(value.to_vec()),
+                _ => {}
+            }
+        }
+    }
+
+    /// True if a Route-Record names `identity`, meaning that the request
+    /// has passed through it already.
+    pub fn is_routed_through(&self, identity: &str) -> bool {
+        self.route_records.iter().any(|record| record.eq_ignore_ascii_case(identity.as_bytes()))
+    }
+}
+
+#[test]
+pub fn collects_proxy_infos_and_route_records() {
+    use super::message_builder::test_payload;
+    let payload = &test_payload(&|mb| {
+        mb.begin_avp(avps::PROXY_INFO)
+            .put_avp_bytes(avps::PROXY_HOST, b"dra1")
+            .put_avp_bytes(avps::PROXY_STATE, b"a");
+        mb.put_avp_bytes(avps::ROUTE_RECORD, b"DRA1.example.com");
+        mb.begin_avp(avps::PROXY_INFO)
+            .put_avp_bytes(avps::PROXY_HOST, b"dra2")
+            .put_avp_bytes(avps::PROXY_STATE, b"bc");
+    });
+    let mut routing = RoutingAvps::new();
+    routing.parse(payload);
+    assert_eq!(&payload[..32], &routing.proxy_infos[..32]);
+    assert_eq!(64, routing.proxy_infos.len());
+    assert_eq!(&payload[56..], &routing.proxy_infos[32..]);
+    assert!(routing.is_routed_through("dra1.example.com"));
+    assert!(!routing.is_routed_through("dra2.example.com"));
+}
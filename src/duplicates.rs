@@ -0,0 +1,144 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use diameter::message_builder::MessageTemplate;
+use diameter::message_header::{EndToEnd, HopByHop};
+
+/// Most answers kept. The oldest ones go first when it is reached, even if
+/// they are still within the window.
+const MAX_ANSWERS: usize = 64 * 1024;
+
+/// Identifies a request. The Origin-Host and End-to-End identifier find
+/// the earlier request, and the Session-Id and CC-Request-Number confirm
+/// that it is the same one, since a client may reuse End-to-End
+/// identifiers sooner than RFC 6733 allows.
+pub struct RequestId<'a> {
+    pub origin_host: &'a [u8],
+    pub end_to_end: EndToEnd,
+    pub session_id: &'a [u8],
+    pub request_number: Option<u32>,
+}
+
+/// Recent answers to requests, such as ones sent again with the T flag
+/// after a failover. Shared by all workers, since a retransmission may come
+/// over another connection. A zero window disables it.
+pub struct DuplicateCache {
+    window: Duration,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    /// Answers by Origin-Host and then End-to-End identifier.
+    answers: HashMap<Vec<u8>, HashMap<u32, Answer>>,
+    /// Keys in the order they were added, for expiry.
+    added: VecDeque<(Instant, Vec<u8>, u32)>,
+}
+
+struct Answer {
+    session_id: Vec<u8>,
+    request_number: Option<u32>,
+    message: MessageTemplate,
+}
+
+impl DuplicateCache {
+    pub fn new(window: Duration) -> Self {
+        DuplicateCache { window, entries: Mutex::new(Entries { answers: HashMap::new(), added: VecDeque::new() }) }
+    }
+
+    /// Writes the answer to an earlier request with the same identity with
+    /// `hop_by_hop` filled in. Returns false if there is none.
+    pub fn replay(&self, request: &RequestId, hop_by_hop: HopByHop, output: &mut Vec<u8>, now: Instant) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.expire(now, self.window);
+        match entries.answers.get(request.origin_host).and_then(|answers| answers.get(&request.end_to_end.0)) {
+            Some(answer) if answer.session_id == request.session_id && answer.request_number == request.request_number => {
+                answer.message.write(output, hop_by_hop, request.end_to_end);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops the answers to an originator, such as when it has restarted.
+    pub fn forget(&self, origin_host: &[u8]) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.answers.remove(origin_host);
+        entries.added.retain(|(_, host, _)| host[..] != *origin_host);
+    }
+
+    /// Keeps `answer`, a complete encoded message, for replaying.
+    pub fn insert(&self, request: &RequestId, answer: &[u8], now: Instant) {
+        if self.window.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.expire(now, self.window);
+        if entries.added.len() >= MAX_ANSWERS {
+            if let Some((_, host, end_to_end)) = entries.added.pop_front() {
+                entries.remove(&host, end_to_end);
+            }
+        }
+        let answer = Answer {
+            session_id: request.session_id.to_vec(),
+            request_number: request.request_number,
+            message: MessageTemplate::from_message(answer),
+        };
+        let origin_host = request.origin_host.to_vec();
+        let previous = entries.answers.entry(origin_host.clone()).or_default().insert(request.end_to_end.0, answer);
+        if previous.is_none() {
+            entries.added.push_back((now, origin_host, request.end_to_end.0));
+        }
+    }
+}
+
+impl Entries {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(&(added, _, _)) = self.added.front() {
+            if now.duration_since(added) < window {
+                break;
+            }
+            let (_, host, end_to_end) = self.added.pop_front().unwrap();
+            self.remove(&host, end_to_end);
+        }
+    }
+
+    fn remove(&mut self, origin_host: &[u8], end_to_end: u32) {
+        if let Some(answers) = self.answers.get_mut(origin_host) {
+            answers.remove(&end_to_end);
+            if answers.is_empty() {
+                self.answers.remove(origin_host);
+            }
+        }
+    }
+}
+
+#[test]
+pub fn replays_answers_within_the_window() {
+    use diameter::commands::DEVICE_WATCHDOG;
+    use diameter::message_builder::MessageBuilder;
+    use diameter::message_flags;
+    let start = Instant::now();
+    let cache = DuplicateCache::new(Duration::from_secs(30));
+    let mut answer = vec![];
+    MessageBuilder::new(&mut answer, message_flags::NONE, DEVICE_WATCHDOG, HopByHop(1), EndToEnd(7));
+    let request = RequestId { origin_host: b"pcef", end_to_end: EndToEnd(7), session_id: b"pcef;1", request_number: Some(0) };
+    cache.insert(&request, &answer, start);
+
+    let mut output = vec![];
+    assert!(!cache.replay(&RequestId { origin_host: b"other", ..request }, HopByHop(2), &mut output, start));
+    assert!(!cache.replay(&RequestId { end_to_end: EndToEnd(8), ..request }, HopByHop(2), &mut output, start));
+    assert!(!cache.replay(&RequestId { session_id: b"pcef;2", ..request }, HopByHop(2), &mut output, start));
+    assert!(!cache.replay(&RequestId { request_number: Some(1), ..request }, HopByHop(2), &mut output, start));
+    assert!(output.is_empty());
+    assert!(cache.replay(&request, HopByHop(2), &mut output, start + Duration::from_secs(29)));
+    assert_eq!(&answer[..12], &output[..12]);
+    assert_eq!(&[0, 0, 0, 2], &output[12..16]);
+    assert!(!cache.replay(&request, HopByHop(2), &mut output, start + Duration::from_secs(30)));
+
+    cache.insert(&request, &answer, start);
+    cache.forget(b"pcef");
+    assert!(!cache.replay(&request, HopByHop(2), &mut output, start));
+}
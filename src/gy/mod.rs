@@ -1,5 +1,5 @@
 use diameter;
-use diameter::ParseError;
+use diameter::{ParseError, ParseErrorKind};
 use diameter::avps::AvpId;
 use diameter::avp_parsers::{parse_avps, parse_u32};
 
@@ -31,12 +31,71 @@ pub mod avps {
     pub const CC_OUTPUT_OCTETS: AvpId = AvpId { code: 414, vendor_id: 0 };
     pub const CC_TOTAL_OCTETS: AvpId = AvpId { code: 421, vendor_id: 0 };
     pub const CC_TIME: AvpId = AvpId { code: 420, vendor_id: 0 };
+    pub const CC_CORRELATION_ID: AvpId = AvpId { code: 411, vendor_id: 0 };
+    pub const CC_SUB_SESSION_ID: AvpId = AvpId { code: 419, vendor_id: 0 };
+    pub const FINAL_UNIT_INDICATION: AvpId = AvpId { code: 430, vendor_id: 0 };
+    pub const REQUESTED_ACTION: AvpId = AvpId { code: 436, vendor_id: 0 };
+    pub const SERVICE_PARAMETER_INFO: AvpId = AvpId { code: 440, vendor_id: 0 };
+    pub const SUBSCRIPTION_ID: AvpId = AvpId { code: 443, vendor_id: 0 };
+    pub const USED_SERVICE_UNIT: AvpId = AvpId { code: 446, vendor_id: 0 };
+    pub const TARIFF_CHANGE_USAGE: AvpId = AvpId { code: 452, vendor_id: 0 };
+    pub const G_S_U_POOL_REFERENCE: AvpId = AvpId { code: 457, vendor_id: 0 };
+    pub const USER_EQUIPMENT_INFO: AvpId = AvpId { code: 458, vendor_id: 0 };
+    pub const SERVICE_CONTEXT_ID: AvpId = AvpId { code: 461, vendor_id: 0 };
+    pub const OC_SUPPORTED_FEATURES: AvpId = AvpId { code: 621, vendor_id: 0 };
+    pub const TGPP_RAT_TYPE: AvpId = AvpId { code: 21, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const PS_FURNISH_CHARGING_INFORMATION: AvpId = AvpId { code: 865, vendor_id: gy::TGPP_VENDOR_ID };
     pub const TIME_QUOTA_THRESHOLD: AvpId = AvpId { code: 868, vendor_id: gy::TGPP_VENDOR_ID };
     pub const VOLUME_QUOTA_THRESHOLD: AvpId = AvpId { code: 869, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const QUOTA_HOLDING_TIME: AvpId = AvpId { code: 871, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const REPORTING_REASON: AvpId = AvpId { code: 872, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const SERVICE_INFORMATION: AvpId = AvpId { code: 873, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const QUOTA_CONSUMPTION_TIME: AvpId = AvpId { code: 881, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const QOS_INFORMATION: AvpId = AvpId { code: 1016, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const UNIT_QUOTA_THRESHOLD: AvpId = AvpId { code: 1226, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const SERVICE_SPECIFIC_INFO: AvpId = AvpId { code: 1249, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const TRIGGER: AvpId = AvpId { code: 1264, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const ENVELOPE: AvpId = AvpId { code: 1266, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const ENVELOPE_REPORTING: AvpId = AvpId { code: 1268, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const TIME_QUOTA_MECHANISM: AvpId = AvpId { code: 1270, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const AF_CORRELATION_INFORMATION: AvpId = AvpId { code: 1276, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const REFUND_INFORMATION: AvpId = AvpId { code: 2022, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const AOC_REQUEST_TYPE: AvpId = AvpId { code: 2055, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const ANNOUNCEMENT_INFORMATION: AvpId = AvpId { code: 3904, vendor_id: gy::TGPP_VENDOR_ID };
+    pub const RELATED_TRIGGER: AvpId = AvpId { code: 3926, vendor_id: gy::TGPP_VENDOR_ID };
 }
 
+/// The AVPs of a CCR, RFC 4006 section 3.1 and 3GPP TS 32.299.
+const CCR_AVPS: &[AvpId] = &[
+    diameter::avps::SESSION_ID, diameter::avps::ORIGIN_HOST, diameter::avps::ORIGIN_REALM, diameter::avps::DESTINATION_REALM,
+    diameter::avps::AUTH_APPLICATION_ID, avps::SERVICE_CONTEXT_ID, avps::CC_REQUEST_TYPE, avps::CC_REQUEST_NUMBER,
+    diameter::avps::DESTINATION_HOST, diameter::avps::USER_NAME, avps::CC_SUB_SESSION_ID, diameter::avps::ACCT_MULTI_SESSION_ID,
+    diameter::avps::ORIGIN_STATE_ID, diameter::avps::EVENT_TIMESTAMP, avps::SUBSCRIPTION_ID, avps::SERVICE_IDENTIFIER,
+    diameter::avps::TERMINATION_CAUSE, avps::REQUESTED_SERVICE_UNIT, avps::REQUESTED_ACTION, avps::USED_SERVICE_UNIT,
+    avps::MULTIPLE_SERVICES_INDICATOR, avps::MULTIPLE_SERVICES_CC, avps::SERVICE_PARAMETER_INFO, avps::CC_CORRELATION_ID,
+    avps::USER_EQUIPMENT_INFO, diameter::avps::PROXY_INFO, diameter::avps::ROUTE_RECORD, avps::SERVICE_INFORMATION,
+    avps::AOC_REQUEST_TYPE, avps::OC_SUPPORTED_FEATURES,
+];
+
+/// The AVPs of a Multiple-Services-Credit-Control, RFC 4006 section 8.16
+/// and 3GPP TS 32.299.
+const MULTIPLE_SERVICES_CC_AVPS: &[AvpId] = &[
+    avps::GRANTED_SERVICE_UNIT, avps::REQUESTED_SERVICE_UNIT, avps::USED_SERVICE_UNIT, avps::TARIFF_CHANGE_USAGE,
+    avps::SERVICE_IDENTIFIER, avps::RATING_GROUP, avps::G_S_U_POOL_REFERENCE, avps::VALIDITY_TIME,
+    diameter::avps::RESULT_CODE, avps::FINAL_UNIT_INDICATION, avps::TIME_QUOTA_THRESHOLD, avps::VOLUME_QUOTA_THRESHOLD,
+    avps::QUOTA_HOLDING_TIME, avps::QUOTA_CONSUMPTION_TIME, avps::REPORTING_REASON, avps::TRIGGER,
+    avps::UNIT_QUOTA_THRESHOLD, avps::PS_FURNISH_CHARGING_INFORMATION, avps::REFUND_INFORMATION,
+    avps::AF_CORRELATION_INFORMATION, avps::ENVELOPE, avps::ENVELOPE_REPORTING, avps::TIME_QUOTA_MECHANISM,
+    avps::SERVICE_SPECIFIC_INFO, avps::QOS_INFORMATION, avps::ANNOUNCEMENT_INFORMATION, avps::TGPP_RAT_TYPE,
+    avps::RELATED_TRIGGER,
+];
+
 pub struct CcRequest {
     pub session_id: Vec<u8>,
+    pub origin_host: Vec<u8>,
+    pub destination_realm: Vec<u8>,
+    pub destination_host: Vec<u8>,
+    pub origin_state_id: Option<u32>,
     pub service_context_id: Vec<u8>,
     pub request_type: Option<u32>,
     pub request_number: Option<u32>,
@@ -52,58 +111,81 @@ pub struct CcService {
 impl CcRequest {
     pub fn new() -> Self {
         CcRequest {
-            request_type: None, request_number: None, session_id: vec![],
+            request_type: None, request_number: None, session_id: vec![], origin_host: vec![],
+            destination_realm: vec![], destination_host: vec![], origin_state_id: None,
             services: vec![], service_context_id: vec![]
         }
     }
 
-    pub fn parse(&mut self, buffer: &[u8]) -> Result<(), ParseError> {
+    /// Parses a CCR. Unknown AVPs with the M bit are refused unless
+    /// `lenient`.
+    pub fn parse(&mut self, buffer: &[u8], lenient: bool) -> Result<(), ParseError> {
         self.session_id.clear();
+        self.origin_host.clear();
+        self.destination_realm.clear();
+        self.destination_host.clear();
+        self.origin_state_id = None;
         self.service_context_id.clear();
         self.request_type = None;
         self.request_number = None;
         self.services.clear();
-        parse_avps(buffer, &parse_ccr_avp, self)
+        parse_avps(buffer, CCR_AVPS, lenient, &move |avp_id, payload, result| parse_ccr_avp(avp_id, payload, result, lenient), self)
     }
 }
 
-fn parse_ccr_avp(avp_id: AvpId, payload: &[u8], result: &mut CcRequest) -> Result<(), ParseError> {
+fn parse_ccr_avp(avp_id: AvpId, payload: &[u8], result: &mut CcRequest, lenient: bool) -> Result<(), ParseError> {
     match avp_id {
         diameter::avps::SESSION_ID => {
-            ok_or(result.session_id.is_empty(), ParseError::AvpOccursTooManyTimes)?;
-            ok_or(!payload.is_empty(), ParseError::InvalidAvpValue)?;
+            ok_or(result.session_id.is_empty(), ParseErrorKind::AvpOccursTooManyTimes)?;
+            ok_or(!payload.is_empty(), ParseErrorKind::InvalidAvpValue)?;
             result.session_id.extend_from_slice(payload);
         }
+        diameter::avps::ORIGIN_HOST => {
+            ok_or(result.origin_host.is_empty(), ParseErrorKind::AvpOccursTooManyTimes)?;
+            result.origin_host.extend_from_slice(payload);
+        }
+        diameter::avps::DESTINATION_REALM => {
+            ok_or(result.destination_realm.is_empty(), ParseErrorKind::AvpOccursTooManyTimes)?;
+            result.destination_realm.extend_from_slice(payload);
+        }
+        diameter::avps::DESTINATION_HOST => {
+            ok_or(result.destination_host.is_empty(), ParseErrorKind::AvpOccursTooManyTimes)?;
+            result.destination_host.extend_from_slice(payload);
+        }
+        diameter::avps::ORIGIN_STATE_ID => {
+            ok_or(result.origin_state_id.is_none(), ParseErrorKind::AvpOccursTooManyTimes)?;
+            result.origin_state_id = Some(parse_u32(payload)?);
+        }
         avps::CC_REQUEST_NUMBER => {
-            ok_or(result.request_number.is_none(), ParseError::AvpOccursTooManyTimes)?;
+            ok_or(result.request_number.is_none(), ParseErrorKind::AvpOccursTooManyTimes)?;
             result.request_number = Some(parse_u32(payload)?);
         }
         avps::CC_REQUEST_TYPE => {
-            ok_or(result.request_type.is_none(), ParseError::AvpOccursTooManyTimes)?;
+            ok_or(result.request_type.is_none(), ParseErrorKind::AvpOccursTooManyTimes)?;
             result.request_type = Some(parse_u32(payload)?);
         }
         avps::MULTIPLE_SERVICES_CC => {
-            result.services.push(parse_service(payload)?);
+            result.services.push(parse_service(payload, lenient)?);
         }
         _ => {}
     }
     Ok(())
 }
 
-fn parse_service(buffer: &[u8]) -> Result<CcService, ParseError> {
+fn parse_service(buffer: &[u8], lenient: bool) -> Result<CcService, ParseError> {
     let mut service = CcService { service_id: None, rating_group: None, units_requested: false };
-    parse_avps(buffer, &parse_service_avp, &mut service)?;
+    parse_avps(buffer, MULTIPLE_SERVICES_CC_AVPS, lenient, &parse_service_avp, &mut service)?;
     Ok(service)
 }
 
 fn parse_service_avp(avp_key: AvpId, payload: &[u8], result: &mut CcService) -> Result<(), ParseError> {
     match avp_key {
         avps::SERVICE_IDENTIFIER => {
-            ok_or(result.service_id.is_none(), ParseError::AvpOccursTooManyTimes)?;
+            ok_or(result.service_id.is_none(), ParseErrorKind::AvpOccursTooManyTimes)?;
             result.service_id = Some(parse_u32(payload)?);
         }
         avps::RATING_GROUP => {
-            ok_or(result.rating_group.is_none(), ParseError::AvpOccursTooManyTimes)?;
+            ok_or(result.rating_group.is_none(), ParseErrorKind::AvpOccursTooManyTimes)?;
             result.rating_group = Some(parse_u32(payload)?);
         }
         avps::REQUESTED_SERVICE_UNIT => {
@@ -122,3 +204,22 @@ fn ok_or<E>(v: bool, err: E) -> Result<(), E> {
         Err(err)
     }
 }
+
+#[test]
+pub fn accepts_the_3gpp_avps_of_multiple_services_credit_control() {
+    use diameter::avp_flags;
+    use diameter::message_builder::test_payload;
+    let payload = test_payload(&|mb| {
+        mb.put_avp_bytes(diameter::avps::SESSION_ID, b"pcef;1")
+            .put_avp_u32(avps::CC_REQUEST_TYPE, 1)
+            .put_avp_bytes_with_flags(avps::AOC_REQUEST_TYPE, avp_flags::MANDATORY, &[0, 0, 0, 1]);
+        let mut service = mb.begin_avp(avps::MULTIPLE_SERVICES_CC);
+        service.put_avp_u32(avps::RATING_GROUP, 10)
+            .put_avp_bytes_with_flags(avps::TGPP_RAT_TYPE, avp_flags::MANDATORY, &[6]);
+        service.begin_avp(avps::QOS_INFORMATION)
+            .put_avp_bytes_with_flags(AvpId { code: 1028, vendor_id: TGPP_VENDOR_ID }, avp_flags::MANDATORY, &[0, 0, 0, 9]);
+    });
+    let mut ccr = CcRequest::new();
+    ccr.parse(&payload, false).unwrap();
+    assert_eq!(Some(10), ccr.services[0].rating_group);
+}
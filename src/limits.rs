@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Counts open connections across all workers, so that the limits hold for
+/// the server as a whole and not per worker. A limit of zero means none.
+pub struct ConnectionLimits {
+    max_total: usize,
+    max_per_address: usize,
+    counts: Mutex<Counts>,
+}
+
+struct Counts {
+    total: usize,
+    per_address: HashMap<IpAddr, usize>,
+}
+
+/// A slot taken by an admitted connection. Given back when dropped.
+pub struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    address: IpAddr,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_total: usize, max_per_address: usize) -> Self {
+        ConnectionLimits {
+            max_total,
+            max_per_address,
+            counts: Mutex::new(Counts { total: 0, per_address: HashMap::new() }),
+        }
+    }
+
+    /// Admits a connection from `address`, or returns `None` if that would
+    /// exceed a limit.
+    pub fn acquire(limits: &Arc<ConnectionLimits>, address: IpAddr) -> Option<ConnectionPermit> {
+        let mut counts = limits.counts.lock().unwrap();
+        if limits.max_total > 0 && counts.total >= limits.max_total {
+            return None;
+        }
+        let count = counts.per_address.entry(address).or_insert(0);
+        if limits.max_per_address > 0 && *count >= limits.max_per_address {
+            return None;
+        }
+        *count += 1;
+        counts.total += 1;
+        Some(ConnectionPermit { limits: limits.clone(), address })
+    }
+
+    fn release(&self, address: IpAddr) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.per_address.get_mut(&address) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_address.remove(&address);
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limits.release(self.address);
+    }
+}
+
+#[test]
+pub fn limits_connections_per_address_and_in_total() {
+    let limits = Arc::new(ConnectionLimits::new(3, 2));
+    let a = IpAddr::from([10, 0, 0, 1]);
+    let b = IpAddr::from([10, 0, 0, 2]);
+    let first = ConnectionLimits::acquire(&limits, a).unwrap();
+    let _second = ConnectionLimits::acquire(&limits, a).unwrap();
+    assert!(ConnectionLimits::acquire(&limits, a).is_none());
+    let _third = ConnectionLimits::acquire(&limits, b).unwrap();
+    assert!(ConnectionLimits::acquire(&limits, b).is_none());
+    drop(first);
+    assert!(ConnectionLimits::acquire(&limits, a).is_some());
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{TcpListener, UnixStream};
+use socket2::{Domain, Protocol, Socket, Type};
+use worker::WorkerHandle;
+
+const LISTEN_BACKLOG: i32 = 1024;
+const SHUTDOWN: Token = Token(usize::MAX);
+
+/// Binds a non-blocking listener. With `reuse_port` several listeners may
+/// share the address, leaving it to the kernel to spread incoming connections
+/// between them. `v6_only` keeps an IPv6 listener from also claiming the
+/// IPv4 port, so that both families can be listened to separately.
+pub fn bind(address: SocketAddr, reuse_port: bool, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if address.is_ipv6() && v6_only {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+/// Accepts connections on all `listeners` and hands them to the workers in
+/// turn. Each connection carries the index of the listener it arrived on.
+/// Returns, closing the listeners, once `shutdown` becomes readable.
+pub fn run_acceptor(mut listeners: Vec<TcpListener>, workers: &[WorkerHandle], shutdown: StdUnixStream) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    for (index, listener) in listeners.iter_mut().enumerate() {
+        poll.registry().register(listener, Token(index), Interest::READABLE)?;
+    }
+    shutdown.set_nonblocking(true)?;
+    let mut shutdown = UnixStream::from_std(shutdown);
+    poll.registry().register(&mut shutdown, SHUTDOWN, Interest::READABLE)?;
+    let mut events = Events::with_capacity(128);
+    let mut next_worker = 0;
+    loop {
+        if let Err(e) = poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter() {
+            if event.token() == SHUTDOWN {
+                return Ok(());
+            }
+            let index = event.token().0;
+            loop {
+                match listeners[index].accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = workers[next_worker].assign(stream, index) {
+                            println!("Failed to hand over connection: {}", e);
+                        }
+                        next_worker = (next_worker + 1) % workers.len();
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        println!("Accept failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
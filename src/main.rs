@@ -1,30 +1,79 @@
 extern crate getopts;
 extern crate byteorder;
 #[macro_use] extern crate bitflags;
+extern crate mio;
+extern crate slab;
+extern crate socket2;
+extern crate core_affinity;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate x509_parser;
+extern crate signal_hook;
 
+mod client;
 mod diameter;
+mod duplicates;
 mod gy;
+mod limits;
+mod listener;
+mod origin_state;
+mod peers;
+mod stats;
+mod tls;
+mod transport;
+mod watchdog;
+mod worker;
 
 use getopts::{Options, Matches};
 use std::process;
 use std::env;
+use std::io::Read;
+use std::os::unix::net::UnixStream;
 use std::sync::Arc;
-use std::io::{Write, Read};
-use std::net::{TcpListener, TcpStream, IpAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use diameter::message_header::MESSAGE_HEADER_SIZE;
 use std::str::FromStr;
 use std::convert::From;
-use diameter::message_builder::MessageBuilder;
-use diameter::message_header::MessageHeader;
+use diameter::message_builder::{patch_avp_u32, MessageBuilder, MessageTemplate};
+use diameter::capabilities::{inband_security, CeRequest, CommonApplications};
+use diameter::avp_iter::AvpIter;
+use diameter::routing::RoutingAvps;
+use duplicates::{DuplicateCache, RequestId};
+use limits::ConnectionLimits;
+use origin_state::OriginStates;
+use peers::{PeerConfig, PeerInfo, PeerTable, Registration};
+use stats::Stats;
+use worker::ConnectionHandle;
+use watchdog::Watchdog;
+use diameter::message_header::{HopByHop, MessageHeader, RequestIds};
 use diameter::message_flags;
 use diameter::result_codes;
 use diameter::avps;
-use diameter::avp_flags;
 use diameter::commands;
 
 struct Config {
-    origin_host: String,
-    origin_realm: String,
+    listeners: Vec<ListenerConfig>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    tls_client_auth: bool,
+    max_connections: usize,
+    max_connections_per_address: usize,
+    idle_timeout: Option<Duration>,
+    message_timeout: Option<Duration>,
+    max_message_size: u32,
+    disconnect_cause: u32,
+    shutdown_timeout: Duration,
+    watchdog_interval: Option<Duration>,
+    watchdog_max_missed: u32,
+    duplicate_policy: DuplicatePolicy,
+    lenient_avps: bool,
+    duplicate_window: Duration,
+    /// Realms served besides the Origin-Realm of each listener.
+    realms: Vec<String>,
+    origin_state_id: u32,
     product_name: String,
     firmware_revision: u32,
     vendor_id: u32,
@@ -37,12 +86,73 @@ struct Config {
     volume_threshold: u32,
 }
 
+/// Which connection stays when a peer that is connected already opens
+/// another one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DuplicatePolicy {
+    /// The election of RFC 6733 section 5.6.4: the new connection is kept if
+    /// our Origin-Host is higher than the peer's, as the winner keeps the
+    /// connection it accepted.
+    Election,
+    KeepOld,
+    KeepNew,
+}
+
+impl DuplicatePolicy {
+    /// True if a peer's new connection replaces its existing one.
+    fn keeps_new(self, local_origin_host: &str, peer_origin_host: &str) -> bool {
+        match self {
+            DuplicatePolicy::Election => local_origin_host.to_ascii_lowercase() > peer_origin_host.to_ascii_lowercase(),
+            DuplicatePolicy::KeepOld => false,
+            DuplicatePolicy::KeepNew => true,
+        }
+    }
+}
+
+/// State shared by all workers.
+#[derive(Clone)]
+struct Shared {
+    limits: Arc<ConnectionLimits>,
+    peers: Arc<PeerTable>,
+    duplicates: Arc<DuplicateCache>,
+    origin_states: Arc<OriginStates>,
+}
+
+/// A local address to accept connections on and the identity presented to
+/// peers connecting to it.
+struct ListenerConfig {
+    address: SocketAddr,
+    origin_host: String,
+    origin_realm: String,
+    tls: bool,
+}
+
 #[derive(Debug)]
 pub enum ClientError {
     IoError(std::io::Error),
     ParseError(diameter::ParseError),
-    ReadBufferOverflow(u32),
-    DisconnectRequested
+    InvalidHeader(diameter::ParseError),
+    MessageTooLarge(u32),
+    TooManyConnections,
+    IdleTimeout,
+    MessageTimeout,
+    CapabilitiesExchangeFailed(u32),
+    CertificateMismatch(String),
+    DisconnectRequested,
+    CapabilitiesExchangeMissing(u32),
+    Disconnected,
+    ShutdownTimeout,
+    WatchdogFailed(u32),
+}
+
+impl ClientError {
+    /// True if the request that caused the error has been answered, or the
+    /// message needed no answer, so that the connection should only be closed
+    /// once all output is sent.
+    pub fn is_answered(&self) -> bool {
+        matches!(*self, ClientError::InvalidHeader(_) | ClientError::CapabilitiesExchangeFailed(_)
+            | ClientError::CapabilitiesExchangeMissing(_) | ClientError::DisconnectRequested | ClientError::Disconnected)
+    }
 }
 
 impl From<std::io::Error> for ClientError {
@@ -57,177 +167,548 @@ impl From<diameter::ParseError> for ClientError {
     }
 }
 
-fn handle_client(mut stream: TcpStream, config: Arc<Config>) {
-    let mut ccr_buffer = gy::CcRequest::new();
-    let mut write_buffer = vec![0u8; 2048];
-    let mut read_buffer = [0u8; 16 * 1024];
-    let address = stream.peer_addr().unwrap();
-    println!("[{}] Client connected", address);
-    loop {
-        if let Err(e) = process_client(&config, &mut stream, &mut read_buffer, &mut write_buffer, &mut ccr_buffer) {
-            match e {
-                ClientError::DisconnectRequested => {
-                    println!("[{}] Client gracefully disconnected", address);
-                }
-                ClientError::IoError(e) => {
-                    println!("[{}] I/O Error: {}", address, e);
-                }
-                ClientError::ReadBufferOverflow(size) => {
-                    println!("[{}] Got a too large packet: {}", address, size);
-                }
-                ClientError::ParseError(e) => {
-                    println!("[{}] Packet parsing failed: {}", address, e.description());
-                }
-            };
-            break;
+impl From<diameter::framing::FrameError> for ClientError {
+    fn from(err: diameter::framing::FrameError) -> Self {
+        match err {
+            diameter::framing::FrameError::InvalidHeader(_, e) => ClientError::InvalidHeader(e),
+            diameter::framing::FrameError::TooLarge(size) => ClientError::MessageTooLarge(size),
         }
     }
 }
 
-fn process_client(config: &Config, stream: &mut TcpStream, read_buffer: &mut [u8], write_buffer: &mut Vec<u8>, ccr_buffer: &mut gy::CcRequest) -> Result<(), ClientError> {
-    let header = read_header(stream)?;
-    let payload = read_payload(&header, stream, read_buffer)?;
-    handle_packet_and_flush(config, &header, payload, write_buffer, ccr_buffer, stream)?;
-    Ok(())
+/// The local end of a connection: the identity of the listener the peer
+/// connected to, and the answers that only differ in their identifiers. These
+/// are encoded once per local address since the CEA advertises it in
+/// Host-IP-Address.
+struct Endpoint {
+    origin_host: String,
+    origin_realm: String,
+    /// The parts of the CEA that do not depend on the CER.
+    cea: MessageTemplate,
+    dwa: MessageTemplate,
+    dpa: MessageTemplate,
+    /// The parts of a successful CCA that do not depend on the CCR.
+    cca: MessageTemplate,
+    dpr: MessageTemplate,
+    dwr: MessageTemplate,
 }
 
-fn read_header(stream: &mut TcpStream) -> Result<MessageHeader, ClientError> {
-    let mut headbuf = [0u8; 20];
-    stream.read_exact(&mut headbuf)?; // TODO: Read data in larger batches
-    Ok(MessageHeader::parse(&headbuf)?)
+impl Endpoint {
+    fn new(config: &Config, listener: &ListenerConfig, local_address: IpAddr) -> Self {
+        Endpoint {
+            origin_host: listener.origin_host.clone(),
+            origin_realm: listener.origin_realm.clone(),
+            cea: MessageTemplate::new(message_flags::NONE, commands::CAPABILITIES_EXCHANGE, |mb| {
+                put_cea_avps(mb, config, listener, local_address);
+            }),
+            dwa: MessageTemplate::new(message_flags::NONE, commands::DEVICE_WATCHDOG, |mb| {
+                mb.put_avp_u32(avps::RESULT_CODE, result_codes::SUCCESS)
+                    .put_avp_bytes(avps::ORIGIN_HOST, listener.origin_host.as_bytes())
+                    .put_avp_bytes(avps::ORIGIN_REALM, listener.origin_realm.as_bytes());
+            }),
+            dpa: MessageTemplate::new(message_flags::NONE, commands::DISCONNECT_PEER, |mb| {
+                mb.put_avp_u32(avps::RESULT_CODE, result_codes::SUCCESS)
+                    .put_avp_bytes(avps::ORIGIN_HOST, listener.origin_host.as_bytes())
+                    .put_avp_bytes(avps::ORIGIN_REALM, listener.origin_realm.as_bytes());
+            }),
+            cca: MessageTemplate::new(message_flags::PROXIABLE, gy::commands::CREDIT_CONTROL, |mb| {
+                mb.put_avp_u32(avps::RESULT_CODE, result_codes::SUCCESS)
+                    .put_avp_bytes(avps::ORIGIN_HOST, listener.origin_host.as_bytes())
+                    .put_avp_bytes(avps::ORIGIN_REALM, listener.origin_realm.as_bytes())
+                    .put_avp_u32(avps::AUTH_APPLICATION_ID, gy::APPLICATION_ID)
+                    .put_avp_u32(avps::ORIGIN_STATE_ID, config.origin_state_id);
+            }),
+            dpr: MessageTemplate::new(message_flags::REQUEST, commands::DISCONNECT_PEER, |mb| {
+                mb.put_avp_bytes(avps::ORIGIN_HOST, listener.origin_host.as_bytes())
+                    .put_avp_bytes(avps::ORIGIN_REALM, listener.origin_realm.as_bytes())
+                    .put_avp_u32(avps::DISCONNECT_CAUSE, config.disconnect_cause);
+            }),
+            dwr: MessageTemplate::new(message_flags::REQUEST, commands::DEVICE_WATCHDOG, |mb| {
+                mb.put_avp_bytes(avps::ORIGIN_HOST, listener.origin_host.as_bytes())
+                    .put_avp_bytes(avps::ORIGIN_REALM, listener.origin_realm.as_bytes());
+            }),
+        }
+    }
 }
 
-fn read_payload<'a>(header: &MessageHeader, stream: &mut TcpStream, buffer: &'a mut [u8]) -> Result<&'a [u8], ClientError> {
-    let plen = header.payload_len();
-    let plen_us = plen as usize;
-    if plen_us > buffer.len() {
-        return Err(ClientError::ReadBufferOverflow(plen));
+/// Applications offered to peers in the capabilities exchange.
+const AUTH_APPLICATION_IDS: &[u32] = &[gy::APPLICATION_ID];
+const ACCT_APPLICATION_IDS: &[u32] = &[];
+const SUPPORTED_VENDOR_IDS: &[u32] = &[gy::TGPP_VENDOR_ID];
+
+fn put_cea_avps(mb: &mut MessageBuilder, config: &Config, listener: &ListenerConfig, local_address: IpAddr) {
+    mb.put_avp_u32(avps::RESULT_CODE, result_codes::SUCCESS)
+        .put_avp_bytes(avps::ORIGIN_HOST, listener.origin_host.as_bytes())
+        .put_avp_bytes(avps::ORIGIN_REALM, listener.origin_realm.as_bytes())
+        .put_avp_u32(avps::VENDOR_ID, config.vendor_id)
+        .put_avp_bytes(avps::PRODUCT_NAME, config.product_name.as_bytes())
+        .put_avp_u32(avps::FIRMWARE_REVISION, config.firmware_revision)
+        .put_avp_u32(avps::ORIGIN_STATE_ID, config.origin_state_id)
+        .put_avp_address(avps::HOST_IP_ADDRESS, local_address);
+}
+
+/// Where a connection is in the peer state machine of RFC 6733 section 5.6.
+/// The server never connects to peers itself, so only the responder side of
+/// it applies: a connection starts out in Closed with the transport up and
+/// only moves to R-Open on a successful CER. Wait-Conn-Ack and I-Open have
+/// no counterpart.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PeerState {
+    /// Connected but no successful CER yet. Anything else is an error.
+    WaitCer,
+    /// R-Open: capabilities exchanged, all messages are handled. A repeated
+    /// CER is answered with a CEA without leaving this state.
+    Open,
+    /// A DPR with the given hop-by-hop identifier was sent and the DPA is
+    /// awaited. Requests are still answered meanwhile.
+    Closing(HopByHop),
+}
+
+/// What a connection knows about the peer at its other end. Handed to the
+/// message handlers along with each request.
+struct Peer {
+    endpoint: Arc<Endpoint>,
+    peers: Arc<PeerTable>,
+    duplicates: Arc<DuplicateCache>,
+    origin_states: Arc<OriginStates>,
+    connection: ConnectionHandle,
+    address: SocketAddr,
+    state: PeerState,
+    tls: bool,
+    start_tls: bool,
+    /// Names from the verified client certificate, once the TLS handshake
+    /// has completed.
+    certificate_names: Option<Vec<String>>,
+    /// Origin-Host from the CER that opened the connection.
+    origin_host: String,
+    request_ids: RequestIds,
+    watchdog: Watchdog,
+    cer: CeRequest,
+    ccr: gy::CcRequest,
+    routing: RoutingAvps,
+}
+
+impl Peer {
+    fn new(endpoint: Arc<Endpoint>, shared: &Shared, connection: ConnectionHandle, tls: bool) -> Self {
+        let address = connection.address();
+        let seed = RandomState::new().hash_one(address);
+        Peer {
+            endpoint, peers: shared.peers.clone(), duplicates: shared.duplicates.clone(),
+            origin_states: shared.origin_states.clone(), connection, address, state: PeerState::WaitCer, tls, start_tls: false, certificate_names: None, origin_host: String::new(),
+            request_ids: RequestIds::new(seed as u32, unix_time()), watchdog: Watchdog::new(seed >> 32),
+            cer: CeRequest::new(), ccr: gy::CcRequest::new(), routing: RoutingAvps::new()
+        }
+    }
+
+    fn certificate_matches(&self, origin_host: &str) -> bool {
+        self.certificate_names.as_ref().is_some_and(|names| tls::name_matches(names, origin_host))
     }
-    stream.read_exact(&mut buffer[0..plen_us])?;
-    Ok(&buffer[0..plen_us])
 }
 
-fn handle_packet_and_flush(config: &Config, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>, ccr: &mut gy::CcRequest, stream: &mut TcpStream) -> Result<(), ClientError> {
-    output.clear();
-    match handle_packet(&config, &header, payload, output, ccr, stream) {
-        Ok(()) => {
-            stream.write_all(&output)?;
-            Ok(())
+impl Drop for Peer {
+    fn drop(&mut self) {
+        if self.state != PeerState::WaitCer {
+            self.peers.unregister(&self.origin_host, self.address);
         }
-        Err(ClientError::DisconnectRequested) => {
-            stream.write_all(&output)?;
-            Err(ClientError::DisconnectRequested)
+    }
+}
+
+fn handle_packet(config: &Config, stats: &Stats, peer: &mut Peer, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>) -> Result<(), ClientError> {
+    if !header.flags.contains(message_flags::REQUEST) {
+        if peer.state == PeerState::WaitCer {
+            return reject_before_cer(&peer.endpoint, header, &[], output);
         }
-        Err(e) => {
-            Err(e)
+        return handle_answer(peer, header);
+    }
+    peer.routing.parse(payload);
+    let start = output.len();
+    let result = handle_request(config, stats, peer, header, payload, output);
+    // RFC 6733 section 6.2: the answer carries the Proxy-Info AVPs of the
+    // request, in the same order.
+    if output.len() > start && !peer.routing.proxy_infos.is_empty() {
+        MessageBuilder::reopen(output, start).put_raw(&peer.routing.proxy_infos);
+    }
+    result
+}
+
+/// Writes the answer to a request. Requests that have passed through us
+/// already, as told by Route-Record, get DIAMETER_LOOP_DETECTED. Before the
+/// capabilities exchange anything but a valid CER closes the connection.
+fn handle_request(config: &Config, stats: &Stats, peer: &mut Peer, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>) -> Result<(), ClientError> {
+    if peer.state == PeerState::WaitCer && header.command_id != commands::CAPABILITIES_EXCHANGE {
+        return reject_before_cer(&peer.endpoint, header, session_id(payload), output);
+    }
+    if header.flags.contains(message_flags::ERROR) {
+        if peer.state == PeerState::WaitCer {
+            return reject_cer(peer, header, output, result_codes::INVALID_HDR_BITS);
         }
+        write_error_answer(&peer.endpoint, header, session_id(payload), result_codes::INVALID_HDR_BITS, None, output);
+        return Ok(());
+    }
+    if peer.routing.is_routed_through(&peer.endpoint.origin_host) {
+        write_error_answer(&peer.endpoint, header, session_id(payload), result_codes::LOOP_DETECTED, None, output);
+        return Ok(());
     }
+    match header.command_id {
+        commands::CAPABILITIES_EXCHANGE => handle_cer(config, peer, header, payload, output)?,
+        commands::DEVICE_WATCHDOG => {
+            peer.endpoint.dwa.write(output, header.hop_by_hop, header.end_to_end);
+        }
+        commands::DISCONNECT_PEER => {
+            peer.endpoint.dpa.write(output, header.hop_by_hop, header.end_to_end);
+            return Err(ClientError::DisconnectRequested);
+        }
+        gy::commands::CREDIT_CONTROL => handle_gy_ccr(config, stats, peer, header, payload, output),
+        _ => handle_unknown(&peer.endpoint, header, payload, output)
+    }
+    Ok(())
 }
 
-fn handle_packet(config: &Config, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>, ccr: &mut gy::CcRequest, stream: &mut TcpStream) -> Result<(), ClientError> {
-    if header.flags.contains(message_flags::REQUEST) {
-        match header.command_id {
-            commands::CAPABILITIES_EXCHANGE => handle_cer(config, header, output, stream)?,
-            commands::DEVICE_WATCHDOG => handle_dwr(config, header, output),
-            commands::DISCONNECT_PEER => {
-                handle_dpr(config, header, output);
-                return Err(ClientError::DisconnectRequested);
+/// The Session-Id of a request, which RFC 6733 section 8.8 puts first, or
+/// nothing if it has none.
+fn session_id(payload: &[u8]) -> &[u8] {
+    AvpIter::new(payload).next()
+        .filter(|(header, _)| header.avp_id == avps::SESSION_ID)
+        .map_or(&[], |(_, value)| value)
+}
+
+/// Handles answers to the requests the server sends itself. Others are
+/// ignored.
+fn handle_answer(peer: &mut Peer, header: &MessageHeader) -> Result<(), ClientError> {
+    match header.command_id {
+        commands::DEVICE_WATCHDOG => {
+            if let Some(missed) = peer.watchdog.answered(header.hop_by_hop) {
+                if missed > 0 {
+                    println!("[{}] Watchdog answered again after {} missed DWA(s)", peer.address, missed);
+                }
             }
-            gy::commands::CREDIT_CONTROL => handle_gy_ccr(config, header, payload, output, ccr),
-            _ => handle_unknown(config, header, output)
         }
+        commands::DISCONNECT_PEER if peer.state == PeerState::Closing(header.hop_by_hop) => {
+            return Err(ClientError::Disconnected);
+        }
+        _ => {}
     }
     Ok(())
 }
 
-fn handle_cer(config: &Config, header: &MessageHeader, output: &mut Vec<u8>, stream: &mut TcpStream) -> Result<(), ClientError> {
-    let local_address = stream.local_addr()?.ip();
-    MessageBuilder::new(output, message_flags::NONE, header.command_id, header.hop_by_hop, header.end_to_end)
-        .put_avp_u32(avps::RESULT_CODE, avp_flags::NONE, result_codes::SUCCESS)
-        .put_avp_bytes(avps::ORIGIN_HOST, avp_flags::NONE, config.origin_host.as_bytes())
-        .put_avp_bytes(avps::ORIGIN_REALM, avp_flags::NONE, config.origin_realm.as_bytes())
-        .put_avp_u32(avps::VENDOR_ID, avp_flags::NONE, config.vendor_id)
-        .put_avp_bytes(avps::PRODUCT_NAME, avp_flags::NONE, config.product_name.as_bytes())
-        .put_avp_u32(avps::FIRMWARE_REVISION, avp_flags::NONE, config.firmware_revision)
-        .put_avp_address(avps::HOST_IP_ADDRESS, avp_flags::NONE, local_address)
-        .put_avp_u32(avps::SUPPORTED_VENDOR_ID, avp_flags::NONE, gy::TGPP_VENDOR_ID)
-        .put_avp_u32(avps::AUTH_APPLICATION_ID, avp_flags::NONE, gy::APPLICATION_ID);
+/// Answers a CER. Only listed peers are accepted if there is a peer table,
+/// others get DIAMETER_UNKNOWN_PEER. The CEA lists the applications and vendors that both sides
+/// support, and the connection is refused with DIAMETER_NO_COMMON_APPLICATION
+/// if there are none. The legacy in-band TLS upgrade, signalled with
+/// Inband-Security-Id, is agreed to when both sides offer it and starts once
+/// the CEA has been sent. Over TLS with client authentication the
+/// Origin-Host must be one the client certificate is issued for; after an
+/// in-band upgrade this can only be checked once the handshake is done, and
+/// peers that use neither get DIAMETER_NO_COMMON_SECURITY. A
+/// repeated CER on an open or closing connection is answered the same way,
+/// except that it cannot start TLS, and leaves its state as it was.
+fn handle_cer(config: &Config, peer: &mut Peer, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>) -> Result<(), ClientError> {
+    if let Err(e) = peer.cer.parse(payload, config.lenient_avps) {
+        return reject_invalid_cer(peer, header, output, &e);
+    }
+    let origin_host = String::from_utf8_lossy(&peer.cer.origin_host).into_owned();
+    let origin_realm = String::from_utf8_lossy(&peer.cer.origin_realm).into_owned();
+    if peer.tls && config.tls_client_auth && !peer.certificate_matches(&origin_host) {
+        return reject_cer(peer, header, output, result_codes::UNKNOWN_PEER);
+    }
+    if !peer.peers.is_allowed(&origin_host, &origin_realm, peer.address.ip()) {
+        return reject_cer(peer, header, output, result_codes::UNKNOWN_PEER);
+    }
+    let applications = peer.cer.common_applications(AUTH_APPLICATION_IDS, ACCT_APPLICATION_IDS);
+    if applications.is_empty() {
+        return reject_cer(peer, header, output, result_codes::NO_COMMON_APPLICATION);
+    }
+    let first_cer = peer.state == PeerState::WaitCer;
+    let inband_tls = first_cer && !peer.tls && config.tls.is_some() && peer.cer.accepts_inband_tls();
+    if !inband_tls && !peer.tls && (config.tls_client_auth || !peer.cer.accepts_no_inband_security()) {
+        return reject_cer(peer, header, output, result_codes::NO_COMMON_SECURITY);
+    }
+    if !first_cer {
+        peer.peers.unregister(&peer.origin_host, peer.address);
+    }
+    let info = PeerInfo {
+        connection: peer.connection.clone(),
+        origin_realm,
+        product_name: String::from_utf8_lossy(&peer.cer.product_name).into_owned(),
+        vendor_id: peer.cer.vendor_id,
+        firmware_revision: peer.cer.firmware_revision,
+    };
+    let keep_new = config.duplicate_policy.keeps_new(&peer.endpoint.origin_host, &origin_host);
+    match peer.peers.register(&origin_host, info, keep_new) {
+        Registration::New => println!("[{}] Peer {} connected", peer.address, origin_host),
+        Registration::Replaced(existing) => {
+            println!("[{}] Peer {} connected again, disconnecting it at {}", peer.address, origin_host, existing.connection.address());
+            existing.connection.disconnect(diameter::disconnect_cause::DO_NOT_WANT_TO_TALK_TO_YOU);
+        }
+        Registration::Refused(existing) => {
+            println!("[{}] Peer {} is connected already at {}", peer.address, origin_host, existing.connection.address());
+            return reject_cer(peer, header, output, result_codes::ELECTION_LOST);
+        }
+    }
+    check_origin_state(peer, origin_host.as_bytes(), peer.cer.origin_state_id);
+    peer.origin_host = origin_host;
+    write_cea(peer, header, output, &applications, inband_tls);
+    peer.start_tls = inband_tls;
+    if first_cer {
+        peer.state = PeerState::Open;
+    }
     Ok(())
 }
 
-fn handle_dwr(config: &Config, header: &MessageHeader, output: &mut Vec<u8>) {
-    MessageBuilder::new(output, message_flags::NONE, header.command_id, header.hop_by_hop, header.end_to_end)
-        .put_avp_u32(avps::RESULT_CODE, avp_flags::NONE, result_codes::SUCCESS)
-        .put_avp_bytes(avps::ORIGIN_HOST, avp_flags::NONE, config.origin_host.as_bytes())
-        .put_avp_bytes(avps::ORIGIN_REALM, avp_flags::NONE, config.origin_realm.as_bytes());
+fn write_cea(peer: &Peer, header: &MessageHeader, output: &mut Vec<u8>, applications: &CommonApplications, inband_tls: bool) {
+    let mut mb = peer.endpoint.cea.write_extended(output, header.hop_by_hop, header.end_to_end);
+    for &vendor_id in peer.cer.common_vendor_ids(SUPPORTED_VENDOR_IDS).iter() {
+        mb.put_avp_u32(avps::SUPPORTED_VENDOR_ID, vendor_id);
+    }
+    for &application_id in applications.auth_application_ids.iter() {
+        mb.put_avp_u32(avps::AUTH_APPLICATION_ID, application_id);
+    }
+    for &application_id in applications.acct_application_ids.iter() {
+        mb.put_avp_u32(avps::ACCT_APPLICATION_ID, application_id);
+    }
+    for application in applications.vendor_specific_application_ids.iter() {
+        mb.begin_avp(avps::VENDOR_SPECIFIC_APPLICATION_ID)
+            .put_avp_u32(avps::VENDOR_ID, application.vendor_id)
+            .put_avp_u32_option(avps::AUTH_APPLICATION_ID, application.auth_application_id)
+            .put_avp_u32_option(avps::ACCT_APPLICATION_ID, application.acct_application_id);
+    }
+    if inband_tls {
+        mb.put_avp_u32(avps::INBAND_SECURITY_ID, inband_security::TLS);
+    }
+}
+
+/// Answers a request that came before the capabilities exchange with
+/// DIAMETER_UNKNOWN_PEER. The connection is closed once it is sent, or right
+/// away if the message was an answer.
+fn reject_before_cer(endpoint: &Endpoint, header: &MessageHeader, session_id: &[u8], output: &mut Vec<u8>) -> Result<(), ClientError> {
+    if header.flags.contains(message_flags::REQUEST) {
+        write_error_answer(endpoint, header, session_id, result_codes::UNKNOWN_PEER, None, output);
+    }
+    Err(ClientError::CapabilitiesExchangeMissing(header.command_id.code))
+}
+
+/// Answers a message whose header is invalid, if it is a request. The
+/// connection is closed once it is sent, as the length of the message cannot
+/// be trusted.
+fn reject_invalid_header(endpoint: &Endpoint, header: &MessageHeader, error: diameter::ParseError, output: &mut Vec<u8>) -> Result<(), ClientError> {
+    if header.flags.contains(message_flags::REQUEST) {
+        write_error_answer(endpoint, header, &[], error.result_code(), Some(&error), output);
+    }
+    Err(ClientError::InvalidHeader(error))
+}
+
+/// Writes the generic answer-message of RFC 6733 section 7.2, with the E bit
+/// set for protocol errors. The Session-Id of the request goes first if it
+/// had one. Returns the builder for adding AVPs of the command.
+fn write_error_answer<'a>(endpoint: &Endpoint, header: &MessageHeader, session_id: &[u8], result_code: u32, error: Option<&diameter::ParseError>, output: &'a mut Vec<u8>) -> MessageBuilder<'a> {
+    let mut flags = header.flags & message_flags::PROXIABLE;
+    if result_codes::is_protocol_error(result_code) {
+        flags.insert(message_flags::ERROR);
+    }
+    let mut mb = MessageBuilder::new(output, flags, header.command_id, header.hop_by_hop, header.end_to_end);
+    mb.put_avp_bytes_nonempty(avps::SESSION_ID, session_id)
+        .put_avp_bytes(avps::ORIGIN_HOST, endpoint.origin_host.as_bytes())
+        .put_avp_bytes(avps::ORIGIN_REALM, endpoint.origin_realm.as_bytes())
+        .put_avp_u32(avps::RESULT_CODE, result_code);
+    if let Some(e) = error {
+        put_parse_error(e, &mut mb);
+    }
+    mb
+}
+
+/// Answers a CER with an error. The connection is closed once it is sent.
+fn reject_cer(peer: &Peer, header: &MessageHeader, output: &mut Vec<u8>, result_code: u32) -> Result<(), ClientError> {
+    if result_codes::is_protocol_error(result_code) {
+        write_error_answer(&peer.endpoint, header, &[], result_code, None, output);
+    } else {
+        let start = peer.endpoint.cea.write(output, header.hop_by_hop, header.end_to_end);
+        patch_avp_u32(&mut output[start..], avps::RESULT_CODE, result_code);
+    }
+    Err(ClientError::CapabilitiesExchangeFailed(result_code))
+}
+
+/// Answers a CER that could not be parsed, with the reason and the offending
+/// AVP. The connection is closed once it is sent.
+fn reject_invalid_cer(peer: &Peer, header: &MessageHeader, output: &mut Vec<u8>, error: &diameter::ParseError) -> Result<(), ClientError> {
+    if result_codes::is_protocol_error(error.result_code()) {
+        write_error_answer(&peer.endpoint, header, &[], error.result_code(), Some(error), output);
+        return Err(ClientError::CapabilitiesExchangeFailed(error.result_code()));
+    }
+    let start = output.len();
+    put_parse_error(error, &mut peer.endpoint.cea.write_extended(output, header.hop_by_hop, header.end_to_end));
+    patch_avp_u32(&mut output[start..], avps::RESULT_CODE, error.result_code());
+    Err(ClientError::CapabilitiesExchangeFailed(error.result_code()))
 }
 
-fn handle_dpr(config: &Config, header: &MessageHeader, output: &mut Vec<u8>) {
-    MessageBuilder::new(output, message_flags::NONE, header.command_id, header.hop_by_hop, header.end_to_end)
-        .put_avp_u32(avps::RESULT_CODE, avp_flags::NONE, result_codes::SUCCESS)
-        .put_avp_bytes(avps::ORIGIN_HOST, avp_flags::NONE, config.origin_host.as_bytes())
-        .put_avp_bytes(avps::ORIGIN_REALM, avp_flags::NONE, config.origin_realm.as_bytes());
+/// Answers a CCR. One that has been answered before, such as one sent
+/// again with the T flag after a failover, gets the same answer again so
+/// that it is not charged twice. Answers are kept without the Proxy-Info
+/// AVPs, which are added from the request being answered.
+fn handle_gy_ccr(config: &Config, stats: &Stats, peer: &mut Peer, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>) {
+    let error = peer.ccr.parse(payload, config.lenient_avps).err();
+    let now = Instant::now();
+    let origin_host = &peer.ccr.origin_host;
+    check_origin_state(peer, origin_host, peer.ccr.origin_state_id);
+    if header.flags.contains(message_flags::RETRANSMITTED) {
+        stats.record_retransmission();
+    }
+    let request = RequestId {
+        origin_host,
+        end_to_end: header.end_to_end,
+        session_id: &peer.ccr.session_id,
+        request_number: peer.ccr.request_number,
+    };
+    if !origin_host.is_empty() && peer.duplicates.replay(&request, header.hop_by_hop, output, now) {
+        stats.record_duplicate();
+        return;
+    }
+    let start = output.len();
+    write_cca(config, &peer.endpoint, header, &peer.ccr, error, output);
+    if !origin_host.is_empty() {
+        peer.duplicates.insert(&request, &output[start..], now);
+    }
 }
 
-fn handle_gy_ccr(config: &Config, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>, ccr: &mut gy::CcRequest) {
-    let result_code = match ccr.parse(payload) {
-        Ok(()) => result_codes::SUCCESS,
-        Err(e) => e.result_code(),
+fn write_cca(config: &Config, endpoint: &Endpoint, header: &MessageHeader, ccr: &gy::CcRequest, error: Option<diameter::ParseError>, output: &mut Vec<u8>) {
+    let result_code = match error {
+        None => check_destination(config, endpoint, &ccr.destination_realm, &ccr.destination_host),
+        Some(ref e) => e.result_code(),
     };
-    let new_flags = header.flags & message_flags::PROXIABLE;
-    let mut mb = MessageBuilder::new(output, new_flags, header.command_id, header.hop_by_hop, header.end_to_end);
-    mb.put_avp_bytes_nonempty(avps::SESSION_ID, avp_flags::NONE, &ccr.session_id);
-    mb.put_avp_u32(avps::RESULT_CODE, avp_flags::NONE, result_code);
-    mb.put_avp_bytes(avps::ORIGIN_HOST, avp_flags::NONE, config.origin_host.as_bytes());
-    mb.put_avp_bytes(avps::ORIGIN_REALM, avp_flags::NONE, config.origin_realm.as_bytes());
-    mb.put_avp_u32(avps::AUTH_APPLICATION_ID, avp_flags::NONE, gy::APPLICATION_ID);
-    mb.put_avp_u32_option(gy::avps::CC_REQUEST_TYPE, avp_flags::NONE, ccr.request_type);
-    mb.put_avp_u32_option(gy::avps::CC_REQUEST_NUMBER, avp_flags::NONE, ccr.request_number);
-    if result_code == result_codes::SUCCESS {
-        mb.put_avp_u32(gy::avps::CC_SESSION_FAILOVER, avp_flags::NONE, 1);
-        mb.put_avp_empty(gy::avps::MULTIPLE_SERVICES_INDICATOR, avp_flags::NONE);
-        for service in ccr.services.iter() {
-            put_service(config, service, &mut mb);
+    if result_codes::is_protocol_error(result_code) {
+        write_error_answer(endpoint, header, &ccr.session_id, result_code, error.as_ref(), output)
+            .put_avp_u32_option(gy::avps::CC_REQUEST_TYPE, ccr.request_type)
+            .put_avp_u32_option(gy::avps::CC_REQUEST_NUMBER, ccr.request_number);
+        return;
+    }
+    let start = output.len();
+    {
+        let new_flags = header.flags & message_flags::PROXIABLE;
+        let mut mb = endpoint.cca.write_with_session_id(output, new_flags, header.hop_by_hop, header.end_to_end, &ccr.session_id);
+        mb.put_avp_u32_option(gy::avps::CC_REQUEST_TYPE, ccr.request_type);
+        mb.put_avp_u32_option(gy::avps::CC_REQUEST_NUMBER, ccr.request_number);
+        match error {
+            None => {
+                mb.put_avp_u32(gy::avps::CC_SESSION_FAILOVER, 1);
+                mb.put_avp_empty(gy::avps::MULTIPLE_SERVICES_INDICATOR);
+                for service in ccr.services.iter() {
+                    put_service(config, service, &mut mb);
+                }
+            }
+            Some(ref e) => put_parse_error(e, &mut mb),
         }
     }
+    if result_code != result_codes::SUCCESS {
+        patch_avp_u32(&mut output[start..], avps::RESULT_CODE, result_code);
+    }
+}
+
+/// Tracks the Origin-State-Id of an originator. A new one means that it has
+/// restarted, so its sessions are gone. The server keeps no session state
+/// but the answers for retransmissions, which are dropped.
+fn check_origin_state(peer: &Peer, origin_host: &[u8], origin_state_id: Option<u32>) {
+    if let Some(state_id) = origin_state_id {
+        if let Some(previous) = peer.origin_states.update(origin_host, state_id) {
+            println!("[{}] {} restarted, Origin-State-Id changed from {} to {}", peer.address, String::from_utf8_lossy(origin_host), previous, state_id);
+            peer.duplicates.forget(origin_host);
+        }
+    }
+}
+
+/// Checks that a request is for us: for one of the realms we serve, and for
+/// our Origin-Host if it names a Destination-Host.
+fn check_destination(config: &Config, endpoint: &Endpoint, destination_realm: &[u8], destination_host: &[u8]) -> u32 {
+    let is_served = |realm: &str| realm.as_bytes().eq_ignore_ascii_case(destination_realm);
+    if !destination_realm.is_empty() && !is_served(&endpoint.origin_realm) && !config.realms.iter().any(|realm| is_served(realm)) {
+        return result_codes::REALM_NOT_SERVED;
+    }
+    if !destination_host.is_empty() && !endpoint.origin_host.as_bytes().eq_ignore_ascii_case(destination_host) {
+        return result_codes::UNABLE_TO_DELIVER;
+    }
+    result_codes::SUCCESS
+}
+
+/// Adds the Error-Message, and the Failed-AVP that RFC 6733 section 7.5
+/// asks for if the error was found in an AVP.
+fn put_parse_error(error: &diameter::ParseError, builder: &mut MessageBuilder) {
+    builder.put_avp_bytes(avps::ERROR_MESSAGE, error.description().as_bytes());
+    if let Some(ref avp) = error.failed_avp {
+        builder.begin_avp(avps::FAILED_AVP)
+            .put_avp_bytes_with_flags(avp.avp_id, avp.flags, &avp.value);
+    }
 }
 
 fn put_service(config: &Config, service: &gy::CcService, builder: &mut MessageBuilder) {
-    let mut sb = builder.begin_avp(gy::avps::MULTIPLE_SERVICES_CC, avp_flags::NONE);
-    sb.put_avp_u32(avps::RESULT_CODE, avp_flags::NONE, result_codes::SUCCESS);
-    sb.put_avp_u32_option(gy::avps::SERVICE_IDENTIFIER, avp_flags::NONE, service.service_id);
-    sb.put_avp_u32_option(gy::avps::RATING_GROUP, avp_flags::NONE, service.rating_group);
+    let mut sb = builder.begin_avp(gy::avps::MULTIPLE_SERVICES_CC);
+    sb.put_avp_u32(avps::RESULT_CODE, result_codes::SUCCESS);
+    sb.put_avp_u32_option(gy::avps::SERVICE_IDENTIFIER, service.service_id);
+    sb.put_avp_u32_option(gy::avps::RATING_GROUP, service.rating_group);
     if service.units_requested {
-        sb.put_avp_u32_nonzero(gy::avps::VALIDITY_TIME, avp_flags::NONE, config.validity_time);
-        sb.put_avp_u32_nonzero(gy::avps::TIME_QUOTA_THRESHOLD, avp_flags::NONE, config.time_threshold);
-        sb.put_avp_u32_nonzero(gy::avps::VOLUME_QUOTA_THRESHOLD, avp_flags::NONE, config.volume_threshold);
-        sb.begin_avp(gy::avps::GRANTED_SERVICE_UNIT, avp_flags::NONE)
-            .put_avp_u32_nonzero(gy::avps::CC_TIME, avp_flags::NONE, config.time)
-            .put_avp_u64_nonzero(gy::avps::CC_INPUT_OCTETS, avp_flags::NONE, config.input_octets)
-            .put_avp_u64_nonzero(gy::avps::CC_OUTPUT_OCTETS, avp_flags::NONE, config.output_octets)
-            .put_avp_u64_nonzero(gy::avps::CC_TOTAL_OCTETS, avp_flags::NONE, config.total_octets);
+        sb.put_avp_u32_nonzero(gy::avps::VALIDITY_TIME, config.validity_time);
+        sb.put_avp_u32_nonzero(gy::avps::TIME_QUOTA_THRESHOLD, config.time_threshold);
+        sb.put_avp_u32_nonzero(gy::avps::VOLUME_QUOTA_THRESHOLD, config.volume_threshold);
+        sb.begin_avp(gy::avps::GRANTED_SERVICE_UNIT)
+            .put_avp_u32_nonzero(gy::avps::CC_TIME, config.time)
+            .put_avp_u64_nonzero(gy::avps::CC_INPUT_OCTETS, config.input_octets)
+            .put_avp_u64_nonzero(gy::avps::CC_OUTPUT_OCTETS, config.output_octets)
+            .put_avp_u64_nonzero(gy::avps::CC_TOTAL_OCTETS, config.total_octets);
     }
 }
 
-fn handle_unknown(config: &Config, header: &MessageHeader, output: &mut Vec<u8>) {
+fn handle_unknown(endpoint: &Endpoint, header: &MessageHeader, payload: &[u8], output: &mut Vec<u8>) {
     let result_code = match header.command_id.application_id {
         diameter::BASE_APPLICATION_ID => result_codes::COMMAND_UNSUPPORTED,
         gy::APPLICATION_ID => result_codes::COMMAND_UNSUPPORTED,
         _ => result_codes::APPLICATION_UNSUPPORTED
     };
-    MessageBuilder::new(output, message_flags::ERROR, header.command_id, header.hop_by_hop, header.end_to_end)
-        .put_avp_u32(avps::RESULT_CODE, avp_flags::NONE, result_code)
-        .put_avp_bytes(avps::ORIGIN_HOST, avp_flags::NONE, config.origin_host.as_bytes())
-        .put_avp_bytes(avps::ORIGIN_REALM, avp_flags::NONE, config.origin_realm.as_bytes());
+    write_error_answer(endpoint, header, session_id(payload), result_code, None, output);
 }
 
 fn parse_args() -> Matches {
     let args: Vec<String> = env::args().collect();
     let program = &args[0];
+    let opts = options();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m }
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+    if matches.opt_present("h") {
+        println!("{}", opts.usage(&format!("Usage: {} [options]", program)));
+        process::exit(0);
+    }
+    matches
+}
+
+fn options() -> Options {
     let mut opts = Options::new();
     opts.optflag("h", "help", "Show this usage message.");
-    opts.optopt("p", "listen-port", "Port to listen on.", "PORT");
-    opts.optopt("l", "listen-address", "Address to listen on.", "ADDRESS");
+    opts.optopt("p", "listen-port", "Port to listen on when not given with the address.", "PORT");
+    opts.optmulti("l", "listen-address", "Address to listen on. May be given several times, optionally with a port and an Origin-Host and Origin-Realm to use instead of the global ones.", "ADDRESS[:PORT][,HOST[,REALM]]");
+    opts.optmulti("", "tls-listen-address", "Address to accept TLS connections on, in the same format as --listen-address. The port defaults to 5658.", "ADDRESS[:PORT][,HOST[,REALM]]");
+    opts.optopt("", "tls-cert", "PEM file with the TLS certificate chain. Also enables in-band TLS after CER/CEA.", "FILE");
+    opts.optopt("", "tls-key", "PEM file with the TLS private key.", "FILE");
+    opts.optopt("", "tls-ca", "PEM file with the CAs that issue client certificates. Makes client certificates mandatory, so that peers must use TLS or in-band TLS, and requires them to be issued for the Origin-Host sent in CER. Needs --tls-cert and --tls-key.", "FILE");
+    opts.optopt("", "max-connections", "Refuse connections beyond NUMBER open ones (default: no limit).", "NUMBER");
+    opts.optopt("", "max-connections-per-ip", "Refuse connections beyond NUMBER open ones from the same address (default: no limit).", "NUMBER");
+    opts.optopt("", "idle-timeout", "Close connections that have not sent anything for SECONDS (default: never).", "SECONDS");
+    opts.optopt("", "message-timeout", "Close connections that take longer than SECONDS to send a whole message (default: 10, 0 for never).", "SECONDS");
+    opts.optopt("", "max-message-size", "Close connections that send a message larger than BYTES (default: 16384).", "BYTES");
+    opts.optopt("", "disconnect-cause", "Disconnect-Cause to send peers in DPR on shutdown (default: 0, REBOOTING).", "NUMBER");
+    opts.optopt("", "shutdown-timeout", "Seconds to wait for peers to answer the DPR on shutdown (default: 5).", "SECONDS");
+    opts.optopt("", "watchdog-interval", "Send a DWR to peers that have been silent for SECONDS, the Tw of RFC 3539 (default: 30, 0 for never).", "SECONDS");
+    opts.optopt("", "watchdog-max-missed", "Close connections after NUMBER DWRs in a row go unanswered (default: 2).", "NUMBER");
+    opts.optopt("t", "threads", "Number of worker threads (default: one per CPU).", "NUMBER");
+    opts.optopt("", "workers", "Run NUMBER workers that each accept on their own SO_REUSEPORT listener instead of sharing one acceptor.", "NUMBER");
+    opts.optflag("", "pin-cpus", "Pin each worker thread to its own CPU core.");
+    opts.optopt("", "stats-interval", "Print statistics every SECONDS (default: never).", "SECONDS");
+    opts.optmulti("", "peer", "Only accept CERs from peers listed with this option, each with its Origin-Host and optionally its Origin-Realm and the addresses it may connect from. Send SIGUSR1 to print the connected peers.", "HOST[,REALM[,ADDRESS...]]");
+    opts.optopt("", "duplicate-connections", "What to do when a connected peer connects again: election (RFC 6733 section 5.6.4, the default), keep-old or keep-new. The losing connection is closed with DPR or, if it is the new one, with DIAMETER_ELECTION_LOST.", "POLICY");
+    opts.optflag("", "lenient-avps", "Ignore unknown AVPs even if they have the M bit set, instead of answering with DIAMETER_AVP_UNSUPPORTED.");
+    opts.optopt("", "duplicate-window", "Answer a CCR with the same Origin-Host, End-to-End identifier, Session-Id and CC-Request-Number as one within the last SECONDS with the same answer again (default: 0, for never).", "SECONDS");
+    opts.optmulti("", "realm", "Also serve requests with this Destination-Realm, besides the Origin-Realm. May be given several times. Requests for other realms get DIAMETER_REALM_NOT_SERVED, and ones with a Destination-Host other than the Origin-Host get DIAMETER_UNABLE_TO_DELIVER.", "REALM");
+    opts.optopt("", "state-file", "File to keep the Origin-State-Id in, which is incremented on every start. Without it the start time is used.", "FILE");
     opts.optopt("", "origin-host", "Value for the Origin-Host AVP.", "STRING");
     opts.optopt("", "origin-realm", "Value for the Origin-Realm AVP.", "STRING");
     opts.optopt("", "product-name", "Value for the Product-Name AVP.", "STRING");
@@ -240,25 +721,28 @@ fn parse_args() -> Matches {
     opts.optopt("", "output-octets", "Value for the CC-Output-Octets AVP.", "BYTES");
     opts.optopt("", "total-octets", "Value for the CC-Total-Octets AVP.", "BYTES");
     opts.optopt("", "volume-threshold", "Value for the Volume-Threshold AVP.", "BYTES");
-
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => { m }
-        Err(e) => {
-            println!("{}", e.to_string());
-            process::exit(1);
-        }
-    };
-    if matches.opt_present("h") {
-        println!("{}", opts.usage(&format!("Usage: {} [options]", program)));
-        process::exit(0);
-    }
-    matches
+    opts
 }
 
 fn parse_config(matches: &Matches) -> Config {
     Config {
-        origin_host: get_str(matches, "origin-host", "dummy_host"),
-        origin_realm: get_str(matches, "origin-realm", "dummy_realm"),
+        listeners: parse_listeners(matches),
+        tls: parse_tls(matches),
+        tls_client_auth: matches.opt_present("tls-ca"),
+        max_connections: get_u64(matches, "max-connections", 0) as usize,
+        max_connections_per_address: get_u64(matches, "max-connections-per-ip", 0) as usize,
+        idle_timeout: get_timeout(matches, "idle-timeout", 0),
+        message_timeout: get_timeout(matches, "message-timeout", 10),
+        max_message_size: parse_max_message_size(matches),
+        disconnect_cause: get_u32(matches, "disconnect-cause", diameter::disconnect_cause::REBOOTING),
+        shutdown_timeout: Duration::from_secs(get_u64(matches, "shutdown-timeout", 5)),
+        watchdog_interval: get_timeout(matches, "watchdog-interval", 30),
+        watchdog_max_missed: get_u32(matches, "watchdog-max-missed", 2),
+        duplicate_policy: parse_duplicate_policy(matches),
+        lenient_avps: matches.opt_present("lenient-avps"),
+        duplicate_window: Duration::from_secs(get_u64(matches, "duplicate-window", 0)),
+        realms: matches.opt_strs("realm"),
+        origin_state_id: parse_origin_state_id(matches),
         product_name: get_str(matches, "product-name", "Dummy OCS"),
         firmware_revision: get_u32(matches, "firmware-revision", 1),
         vendor_id: get_u32(matches, "vendor-id", 0xFFFFFFFF),
@@ -272,6 +756,106 @@ fn parse_config(matches: &Matches) -> Config {
     }
 }
 
+fn parse_listeners(matches: &Matches) -> Vec<ListenerConfig> {
+    let port = matches.opt_str("p").map_or(3868, |x| x.parse::<u16>().unwrap());
+    let mut specs = matches.opt_strs("l");
+    let tls_specs = matches.opt_strs("tls-listen-address");
+    if specs.is_empty() && tls_specs.is_empty() {
+        specs.push("127.0.0.1".to_string());
+    }
+    let mut listeners: Vec<ListenerConfig> = specs.iter().map(|spec| parse_listener(matches, spec, port, false)).collect();
+    listeners.extend(tls_specs.iter().map(|spec| parse_listener(matches, spec, 5658, true)));
+    listeners
+}
+
+fn parse_listener(matches: &Matches, spec: &str, default_port: u16, tls: bool) -> ListenerConfig {
+    let mut parts = spec.split(',');
+    ListenerConfig {
+        address: parse_socket_address(parts.next().unwrap(), default_port),
+        origin_host: parts.next().map_or_else(|| get_str(matches, "origin-host", "dummy_host"), |x| x.to_string()),
+        origin_realm: parts.next().map_or_else(|| get_str(matches, "origin-realm", "dummy_realm"), |x| x.to_string()),
+        tls,
+    }
+}
+
+fn parse_duplicate_policy(matches: &Matches) -> DuplicatePolicy {
+    match matches.opt_str("duplicate-connections").as_deref() {
+        None | Some("election") => DuplicatePolicy::Election,
+        Some("keep-old") => DuplicatePolicy::KeepOld,
+        Some("keep-new") => DuplicatePolicy::KeepNew,
+        Some(other) => {
+            println!("Unknown duplicate connection policy: {}", other);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_peers(matches: &Matches) -> Vec<PeerConfig> {
+    matches.opt_strs("peer").iter().map(|spec| {
+        let mut parts = spec.split(',');
+        PeerConfig {
+            origin_host: parts.next().unwrap().to_string(),
+            origin_realm: parts.next().unwrap_or("").to_string(),
+            addresses: parts.map(|x| IpAddr::from_str(x).unwrap()).collect(),
+        }
+    }).collect()
+}
+
+fn parse_tls(matches: &Matches) -> Option<Arc<rustls::ServerConfig>> {
+    let cert = matches.opt_str("tls-cert");
+    let key = matches.opt_str("tls-key");
+    let client_ca = matches.opt_str("tls-ca");
+    match (cert, key) {
+        (Some(cert), Some(key)) => match tls::load_server_config(&cert, &key, client_ca.as_deref()) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                println!("Failed to load TLS certificate or key: {}", e);
+                process::exit(1);
+            }
+        },
+        (None, None) if client_ca.is_none() && !matches.opt_present("tls-listen-address") => None,
+        _ => {
+            println!("TLS needs both --tls-cert and --tls-key");
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_origin_state_id(matches: &Matches) -> u32 {
+    match matches.opt_str("state-file") {
+        Some(path) => origin_state::next_origin_state_id(&path).unwrap_or_else(|e| {
+            println!("Failed to update the state file {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => unix_time() as u32,
+    }
+}
+
+/// Accepts `ADDRESS`, `IPV4:PORT`, `[IPV6]` and `[IPV6]:PORT`.
+fn parse_socket_address(value: &str, default_port: u16) -> SocketAddr {
+    SocketAddr::from_str(value).unwrap_or_else(|_| {
+        let address = value.trim_start_matches('[').trim_end_matches(']');
+        SocketAddr::new(IpAddr::from_str(address).unwrap(), default_port)
+    })
+}
+
+fn parse_max_message_size(matches: &Matches) -> u32 {
+    let size = get_u32(matches, "max-message-size", 16 * 1024);
+    if size < MESSAGE_HEADER_SIZE {
+        println!("The maximum message size must be at least {} bytes", MESSAGE_HEADER_SIZE);
+        process::exit(1);
+    }
+    size
+}
+
+/// Reads a number of seconds where zero means no timeout.
+fn get_timeout(matches: &Matches, key: &str, def: u64) -> Option<Duration> {
+    match get_u64(matches, key, def) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
 fn get_str(matches: &Matches, key: &str, def: &str) -> String {
     matches.opt_str(key).unwrap_or(def.to_string())
 }
@@ -286,22 +870,336 @@ fn get_u64(matches: &Matches, key: &str, def: u64) -> u64 {
 
 fn main() {
     let opt_matches = parse_args();
-    let port = opt_matches.opt_str("p").map_or(3868, |x| x.parse::<u16>().unwrap());
-    let address = IpAddr::from_str(&*get_str(&opt_matches, "l", "127.0.0.1")).unwrap();
+    let threads = opt_matches.opt_str("t").map_or_else(default_threads, |x| x.parse::<usize>().unwrap());
+    let reuse_port_workers = opt_matches.opt_str("workers").map(|x| x.parse::<usize>().unwrap());
+    let stats_interval = get_u64(&opt_matches, "stats-interval", 0);
     let config = Arc::new(parse_config(&opt_matches));
+    let cores = if opt_matches.opt_present("pin-cpus") {
+        core_affinity::get_core_ids().unwrap_or_default()
+    } else {
+        vec![]
+    };
 
-    let listener = TcpListener::bind((address, port)).unwrap();
-    println!("Listening to {}:{}", address, port);
+    let worker_count = reuse_port_workers.unwrap_or(threads);
+    let shared = Shared {
+        limits: Arc::new(ConnectionLimits::new(config.max_connections, config.max_connections_per_address)),
+        peers: Arc::new(PeerTable::new(parse_peers(&opt_matches))),
+        duplicates: Arc::new(DuplicateCache::new(config.duplicate_window)),
+        origin_states: Arc::new(OriginStates::new()),
+    };
+    peers::spawn_dumper(shared.peers.clone());
+    let stats: Vec<Arc<stats::Stats>> = (0..worker_count).map(|_| Arc::new(stats::Stats::new())).collect();
+    let workers: Vec<worker::WorkerHandle> = (0..worker_count)
+        .map(|id| {
+            let listeners = if reuse_port_workers.is_some() { bind_listeners(&config, true) } else { vec![] };
+            let core = if cores.is_empty() { None } else { Some(cores[id % cores.len()]) };
+            worker::spawn(id, config.clone(), stats[id].clone(), shared.clone(), listeners, core).unwrap()
+        })
+        .collect();
+    if stats_interval > 0 {
+        stats::spawn_reporter(stats, Duration::from_secs(stats_interval));
+    }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let config = config.clone();
-                thread::spawn(move || handle_client(stream, config));
-            }
-            Err(e) => {
-                println!("Accept failed: {}", e.to_string());
-            }
+    let signals = register_shutdown_signals().unwrap();
+    let listeners = if reuse_port_workers.is_some() { vec![] } else { bind_listeners(&config, false) };
+    for listener in config.listeners.iter() {
+        let transport = if listener.tls { "TLS" } else { "TCP" };
+        println!("Listening to {} ({}) as {} in realm {}", listener.address, transport, listener.origin_host, listener.origin_realm);
+    }
+    if reuse_port_workers.is_some() {
+        println!("Serving with {} SO_REUSEPORT workers", worker_count);
+        let _ = (&signals).read(&mut [0u8]);
+    } else {
+        println!("Serving with {} worker threads", worker_count);
+        if let Err(e) = listener::run_acceptor(listeners, &workers, signals) {
+            println!("Acceptor failed: {}", e);
+            process::exit(1);
         }
     }
+
+    println!("Shutting down");
+    for worker in workers.iter() {
+        worker.shutdown();
+    }
+    for worker in workers {
+        worker.join();
+    }
+}
+
+/// Makes SIGTERM and SIGINT write to the returned socket, which becomes
+/// readable once shutdown is requested.
+fn register_shutdown_signals() -> std::io::Result<UnixStream> {
+    let (receiver, sender) = UnixStream::pair()?;
+    for &signal in &[signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::low_level::pipe::register(signal, sender.try_clone()?)?;
+    }
+    Ok(receiver)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Binds one listener per configured address. IPv6 listeners are made
+/// IPv6-only when an IPv4 listener uses the same port, so that both can be
+/// bound side by side.
+fn bind_listeners(config: &Config, reuse_port: bool) -> Vec<mio::net::TcpListener> {
+    config.listeners.iter().map(|listener| {
+        let port = listener.address.port();
+        let v6_only = config.listeners.iter().any(|other| other.address.is_ipv4() && other.address.port() == port);
+        listener::bind(listener.address, reuse_port, v6_only).unwrap()
+    }).collect()
+}
+
+#[cfg(test)]
+fn test_config(args: &[&str]) -> Config {
+    parse_config(&options().parse(args).unwrap())
+}
+
+#[cfg(test)]
+fn test_peer(config: &Config, peers: Vec<PeerConfig>) -> Peer {
+    let shared = Shared {
+        limits: Arc::new(ConnectionLimits::new(0, 0)),
+        peers: Arc::new(PeerTable::new(peers)),
+        duplicates: Arc::new(DuplicateCache::new(config.duplicate_window)),
+        origin_states: Arc::new(OriginStates::new()),
+    };
+    let endpoint = Arc::new(Endpoint::new(config, &config.listeners[0], IpAddr::from([127, 0, 0, 1])));
+    Peer::new(endpoint, &shared, ConnectionHandle::detached(SocketAddr::from(([10, 0, 0, 1], 40000))), false)
+}
+
+/// Hands an encoded message to `handle_packet` and returns the result and
+/// what was written.
+#[cfg(test)]
+fn test_packet(config: &Config, peer: &mut Peer, message: &[u8]) -> (Result<(), ClientError>, Vec<u8>) {
+    let mut header_bytes = [0; MESSAGE_HEADER_SIZE as usize];
+    header_bytes.copy_from_slice(&message[..MESSAGE_HEADER_SIZE as usize]);
+    let header = MessageHeader::parse(&header_bytes).unwrap();
+    let mut output = vec![];
+    let result = handle_packet(config, &Stats::new(), peer, &header, &message[MESSAGE_HEADER_SIZE as usize..], &mut output);
+    (result, output)
+}
+
+#[cfg(test)]
+fn test_message(flags: message_flags::MessageFlags, command_id: commands::CommandId, hop_by_hop: u32, build: &dyn Fn(&mut MessageBuilder)) -> Vec<u8> {
+    use diameter::message_header::EndToEnd;
+    let mut message = vec![];
+    build(&mut MessageBuilder::new(&mut message, flags, command_id, HopByHop(hop_by_hop), EndToEnd(hop_by_hop)));
+    message
+}
+
+#[cfg(test)]
+fn test_cer(origin_host: &str) -> Vec<u8> {
+    test_message(message_flags::REQUEST, commands::CAPABILITIES_EXCHANGE, 1, &|mb| {
+        mb.put_avp_bytes(avps::ORIGIN_HOST, origin_host.as_bytes())
+            .put_avp_bytes(avps::ORIGIN_REALM, b"example.com")
+            .put_avp_u32(avps::AUTH_APPLICATION_ID, gy::APPLICATION_ID);
+    })
+}
+
+#[cfg(test)]
+fn test_result_code(answer: &[u8]) -> Option<u32> {
+    AvpIter::new(&answer[MESSAGE_HEADER_SIZE as usize..]).find_first(avps::RESULT_CODE)
+        .map(|value| diameter::avp_parsers::parse_u32(value).unwrap())
+}
+
+#[test]
+pub fn rejects_messages_before_cer() {
+    let config = test_config(&[]);
+    let mut peer = test_peer(&config, vec![]);
+    let (result, answer) = test_packet(&config, &mut peer, &test_message(message_flags::REQUEST, commands::DEVICE_WATCHDOG, 1, &|_| {}));
+    assert!(matches!(result, Err(ClientError::CapabilitiesExchangeMissing(280))));
+    assert_eq!(Some(result_codes::UNKNOWN_PEER), test_result_code(&answer));
+    assert_eq!(message_flags::ERROR.bits(), answer[4]);
+
+    let dwa = test_message(message_flags::NONE, commands::DEVICE_WATCHDOG, 2, &|_| {});
+    let (result, answer) = test_packet(&config, &mut peer, &dwa);
+    assert!(matches!(result, Err(ClientError::CapabilitiesExchangeMissing(280))));
+    assert!(answer.is_empty());
+    assert_eq!(PeerState::WaitCer, peer.state);
+
+    let erroneous = message_flags::REQUEST | message_flags::ERROR;
+    let (result, answer) = test_packet(&config, &mut peer, &test_message(erroneous, commands::DEVICE_WATCHDOG, 3, &|_| {}));
+    assert!(matches!(result, Err(ClientError::CapabilitiesExchangeMissing(280))));
+    assert_eq!(Some(result_codes::UNKNOWN_PEER), test_result_code(&answer));
+
+    let mut cer = test_cer("pcef.example.com");
+    cer[4] |= message_flags::ERROR.bits();
+    let (result, answer) = test_packet(&config, &mut peer, &cer);
+    assert!(matches!(result, Err(ClientError::CapabilitiesExchangeFailed(result_codes::INVALID_HDR_BITS))));
+    assert_eq!(Some(result_codes::INVALID_HDR_BITS), test_result_code(&answer));
+    assert_eq!(PeerState::WaitCer, peer.state);
+}
+
+#[test]
+pub fn answers_a_repeated_cer_while_open() {
+    let config = test_config(&[]);
+    let mut peer = test_peer(&config, vec![]);
+    let (result, answer) = test_packet(&config, &mut peer, &test_cer("pcef.example.com"));
+    assert!(result.is_ok());
+    assert_eq!(Some(result_codes::SUCCESS), test_result_code(&answer));
+    assert_eq!(PeerState::Open, peer.state);
+
+    let (result, answer) = test_packet(&config, &mut peer, &test_cer("pcef.example.com"));
+    assert!(result.is_ok());
+    assert_eq!(Some(result_codes::SUCCESS), test_result_code(&answer));
+    assert_eq!(PeerState::Open, peer.state);
+    assert_eq!("pcef.example.com", peer.origin_host);
+}
+
+#[test]
+pub fn closes_on_the_matching_dpa_only() {
+    let config = test_config(&[]);
+    let mut peer = test_peer(&config, vec![]);
+    assert!(test_packet(&config, &mut peer, &test_cer("pcef.example.com")).0.is_ok());
+    peer.state = PeerState::Closing(HopByHop(5));
+
+    let (result, answer) = test_packet(&config, &mut peer, &test_message(message_flags::REQUEST, commands::DEVICE_WATCHDOG, 3, &|_| {}));
+    assert!(result.is_ok());
+    assert_eq!(Some(result_codes::SUCCESS), test_result_code(&answer));
+    assert!(test_packet(&config, &mut peer, &test_cer("pcef.example.com")).0.is_ok());
+    assert_eq!(PeerState::Closing(HopByHop(5)), peer.state);
+
+    let dpa = |hop_by_hop: u32| test_message(message_flags::NONE, commands::DISCONNECT_PEER, hop_by_hop, &|mb| {
+        mb.put_avp_u32(avps::RESULT_CODE, result_codes::SUCCESS);
+    });
+    assert!(test_packet(&config, &mut peer, &dpa(6)).0.is_ok());
+    assert!(matches!(test_packet(&config, &mut peer, &dpa(5)).0, Err(ClientError::Disconnected)));
+}
+
+#[test]
+pub fn elects_the_connection_to_keep() {
+    assert!(DuplicatePolicy::Election.keeps_new("ocs.example.com", "OCS.example.CO"));
+    assert!(!DuplicatePolicy::Election.keeps_new("OCS.example.com", "pcef.example.com"));
+    assert!(!DuplicatePolicy::Election.keeps_new("ocs.example.com", "OCS.example.com"));
+    assert!(!DuplicatePolicy::KeepOld.keeps_new("ocs.example.com", "a.example.com"));
+    assert!(DuplicatePolicy::KeepNew.keeps_new("a.example.com", "ocs.example.com"));
+}
+
+#[cfg(test)]
+fn test_ccr(hop_by_hop: u32, build: &dyn Fn(&mut MessageBuilder)) -> Vec<u8> {
+    test_message(message_flags::REQUEST | message_flags::PROXIABLE, gy::commands::CREDIT_CONTROL, hop_by_hop, &|mb| {
+        mb.put_avp_bytes(avps::SESSION_ID, b"pcef;1;2")
+            .put_avp_bytes(avps::ORIGIN_HOST, b"pcef.example.com")
+            .put_avp_bytes(avps::ORIGIN_REALM, b"example.com")
+            .put_avp_u32(avps::AUTH_APPLICATION_ID, gy::APPLICATION_ID)
+            .put_avp_bytes(gy::avps::SERVICE_CONTEXT_ID, b"32251@3gpp.org")
+            .put_avp_u32(gy::avps::CC_REQUEST_TYPE, 1)
+            .put_avp_u32(gy::avps::CC_REQUEST_NUMBER, 0);
+        build(mb);
+    })
+}
+
+#[test]
+pub fn protocol_errors_keep_the_session_id() {
+    let config = test_config(&[]);
+    let mut peer = test_peer(&config, vec![]);
+    assert!(test_packet(&config, &mut peer, &test_cer("pcef.example.com")).0.is_ok());
+    let (result, answer) = test_packet(&config, &mut peer, &test_ccr(2, &|mb| {
+        mb.put_raw(&[0, 0, 0x27, 0x0F, 0x41, 0, 0, 12, 0, 0, 0, 1]);
+    }));
+    assert!(result.is_ok());
+    assert_eq!((message_flags::PROXIABLE | message_flags::ERROR).bits(), answer[4]);
+    assert_eq!(Some(diameter::ParseErrorKind::InvalidAvpBits.result_code()), test_result_code(&answer));
+    assert_eq!(Some((avps::SESSION_ID, &b"pcef;1;2"[..])), AvpIter::new(&answer[MESSAGE_HEADER_SIZE as usize..]).next().map(|(header, value)| (header.avp_id, value)));
+    assert_eq!(Some(&[0, 0, 0, 0][..]), AvpIter::new(&answer[MESSAGE_HEADER_SIZE as usize..]).find_first(gy::avps::CC_REQUEST_NUMBER));
+}
+
+#[test]
+pub fn refuses_unknown_peers_with_the_e_bit() {
+    let config = test_config(&[]);
+    let allowed = PeerConfig { origin_host: "pcef.example.com".to_string(), origin_realm: String::new(), addresses: vec![] };
+    let mut peer = test_peer(&config, vec![allowed]);
+    let (result, answer) = test_packet(&config, &mut peer, &test_cer("other.example.com"));
+    assert!(matches!(result, Err(ClientError::CapabilitiesExchangeFailed(result_codes::UNKNOWN_PEER))));
+    assert_eq!(message_flags::ERROR.bits(), answer[4]);
+    assert_eq!(Some(result_codes::UNKNOWN_PEER), test_result_code(&answer));
+}
+
+#[test]
+pub fn checks_the_destination_of_ccrs() {
+    let config = test_config(&["--realm", "other.example.com"]);
+    let peer = test_peer(&config, vec![]);
+    let check = |realm: &[u8], host: &[u8]| check_destination(&config, &peer.endpoint, realm, host);
+    assert_eq!(result_codes::SUCCESS, check(b"", b""));
+    assert_eq!(result_codes::SUCCESS, check(b"dummy_realm", b""));
+    assert_eq!(result_codes::SUCCESS, check(b"DUMMY_REALM", b""));
+    assert_eq!(result_codes::SUCCESS, check(b"Other.Example.Com", b""));
+    assert_eq!(result_codes::REALM_NOT_SERVED, check(b"example.com", b""));
+    assert_eq!(result_codes::REALM_NOT_SERVED, check(b"example.com", b"dummy_host"));
+    assert_eq!(result_codes::SUCCESS, check(b"dummy_realm", b"Dummy_Host"));
+    assert_eq!(result_codes::SUCCESS, check(b"", b"dummy_host"));
+    assert_eq!(result_codes::UNABLE_TO_DELIVER, check(b"dummy_realm", b"other_host"));
+    assert_eq!(result_codes::UNABLE_TO_DELIVER, check(b"", b"other_host"));
+}
+
+#[test]
+pub fn requires_tls_for_client_authentication() {
+    let mut config = test_config(&[]);
+    config.tls_client_auth = true;
+    let mut peer = test_peer(&config, vec![]);
+    let (result, answer) = test_packet(&config, &mut peer, &test_cer("pcef.example.com"));
+    assert!(matches!(result, Err(ClientError::CapabilitiesExchangeFailed(result_codes::NO_COMMON_SECURITY))));
+    assert_eq!(Some(result_codes::NO_COMMON_SECURITY), test_result_code(&answer));
+    assert_eq!(PeerState::WaitCer, peer.state);
+}
+
+#[test]
+pub fn appends_proxy_infos_and_detects_loops() {
+    let config = test_config(&["--duplicate-window", "30"]);
+    let mut peer = test_peer(&config, vec![]);
+    assert!(test_packet(&config, &mut peer, &test_cer("pcef.example.com")).0.is_ok());
+    let put_proxy_infos = |mb: &mut MessageBuilder, state: &[u8]| {
+        mb.begin_avp(avps::PROXY_INFO)
+            .put_avp_bytes(avps::PROXY_HOST, b"dra1.example.com")
+            .put_avp_bytes(avps::PROXY_STATE, state);
+        mb.begin_avp(avps::PROXY_INFO)
+            .put_avp_bytes(avps::PROXY_HOST, b"dra2.example.com")
+            .put_avp_bytes(avps::PROXY_STATE, b"b");
+    };
+    let proxy_infos = |message: &[u8]| -> Vec<Vec<u8>> {
+        AvpIter::new(&message[MESSAGE_HEADER_SIZE as usize..]).find_all(avps::PROXY_INFO).map(|value| value.to_vec()).collect()
+    };
+    let ends_with_proxy_infos = |message: &[u8]| {
+        let avp_ids: Vec<_> = AvpIter::new(&message[MESSAGE_HEADER_SIZE as usize..]).map(|(header, _)| header.avp_id).collect();
+        avp_ids.ends_with(&[avps::PROXY_INFO, avps::PROXY_INFO])
+    };
+
+    let ccr = test_ccr(2, &|mb| put_proxy_infos(mb, b"a"));
+    let (result, answer) = test_packet(&config, &mut peer, &ccr);
+    assert!(result.is_ok());
+    assert_eq!(Some(result_codes::SUCCESS), test_result_code(&answer));
+    assert!(ends_with_proxy_infos(&answer));
+    assert_eq!(proxy_infos(&ccr), proxy_infos(&answer));
+
+    let retransmission = test_ccr(2, &|mb| put_proxy_infos(mb, b"c"));
+    let (result, replayed) = test_packet(&config, &mut peer, &retransmission);
+    assert!(result.is_ok());
+    assert!(ends_with_proxy_infos(&replayed));
+    assert_eq!(proxy_infos(&retransmission), proxy_infos(&replayed));
+
+    let mut invalid = test_ccr(3, &|mb| put_proxy_infos(mb, b"a"));
+    invalid[4] |= message_flags::ERROR.bits();
+    let (result, answer) = test_packet(&config, &mut peer, &invalid);
+    assert!(result.is_ok());
+    assert_eq!((message_flags::PROXIABLE | message_flags::ERROR).bits(), answer[4]);
+    assert_eq!(Some(result_codes::INVALID_HDR_BITS), test_result_code(&answer));
+    assert!(ends_with_proxy_infos(&answer));
+    assert_eq!(proxy_infos(&invalid), proxy_infos(&answer));
+
+    let looped = test_ccr(4, &|mb| {
+        mb.put_avp_bytes(avps::ROUTE_RECORD, b"dra1.example.com")
+            .put_avp_bytes(avps::ROUTE_RECORD, b"dummy_host");
+        put_proxy_infos(mb, b"a");
+    });
+    let (result, answer) = test_packet(&config, &mut peer, &looped);
+    assert!(result.is_ok());
+    assert_eq!((message_flags::PROXIABLE | message_flags::ERROR).bits(), answer[4]);
+    assert_eq!(Some(result_codes::LOOP_DETECTED), test_result_code(&answer));
+    assert!(ends_with_proxy_infos(&answer));
+    assert_eq!(proxy_infos(&looped), proxy_infos(&answer));
 }
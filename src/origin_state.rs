@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::sync::RwLock;
+
+/// Reads the Origin-State-Id of the previous run from `path` and stores and
+/// returns the next one, so that it increases with every restart as RFC 6733
+/// section 8.16 requires. A missing or empty file counts as a first run.
+/// The new id is synced to disk before it replaces the old one, so that a
+/// crash leaves one or the other.
+pub fn next_origin_state_id(path: &str) -> io::Result<u32> {
+    let previous = match fs::read_to_string(path) {
+        Ok(ref contents) if contents.trim().is_empty() => 0,
+        Ok(contents) => contents.trim().parse::<u32>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    let next = previous.wrapping_add(1);
+    let temporary = format!("{}.tmp", path);
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(format!("{}\n", next).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    Ok(next)
+}
+
+/// The last Origin-State-Id seen from each originator, by Origin-Host.
+/// Shared by all workers, since an originator may send over several
+/// connections.
+pub struct OriginStates {
+    ids: RwLock<HashMap<Vec<u8>, u32>>,
+}
+
+impl OriginStates {
+    pub fn new() -> Self {
+        OriginStates { ids: RwLock::new(HashMap::new()) }
+    }
+
+    /// Records the Origin-State-Id an originator sent. Returns the one it
+    /// had before if it changed, which means that it has restarted. Only
+    /// takes the write lock when the id is new or has changed, which is
+    /// rare next to the CCRs that repeat it.
+    pub fn update(&self, origin_host: &[u8], state_id: u32) -> Option<u32> {
+        if self.ids.read().unwrap().get(origin_host) == Some(&state_id) {
+            return None;
+        }
+        let mut ids = self.ids.write().unwrap();
+        match ids.get_mut(origin_host) {
+            Some(previous) if *previous != state_id => Some(mem::replace(previous, state_id)),
+            Some(_) => None,
+            None => {
+                ids.insert(origin_host.to_vec(), state_id);
+                None
+            }
+        }
+    }
+}
+
+#[test]
+pub fn detects_changed_state_ids() {
+    let states = OriginStates::new();
+    assert_eq!(None, states.update(b"pcef", 5));
+    assert_eq!(None, states.update(b"pcef", 5));
+    assert_eq!(None, states.update(b"other", 1));
+    assert_eq!(Some(5), states.update(b"pcef", 6));
+    assert_eq!(None, states.update(b"pcef", 6));
+    assert_eq!(Some(6), states.update(b"pcef", 5));
+}
+
+#[test]
+pub fn increments_the_stored_state_id() {
+    let path = std::env::temp_dir().join(format!("ocs-server-dummy-state-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+    assert_eq!(1, next_origin_state_id(path).unwrap());
+    assert_eq!(2, next_origin_state_id(path).unwrap());
+    assert_eq!("2\n", fs::read_to_string(path).unwrap());
+    fs::write(path, "").unwrap();
+    assert_eq!(1, next_origin_state_id(path).unwrap());
+    fs::write(path, "garbage").unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, next_origin_state_id(path).unwrap_err().kind());
+    fs::remove_file(path).unwrap();
+}
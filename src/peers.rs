@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+use worker::ConnectionHandle;
+
+/// A peer that is allowed to connect. An empty `origin_realm` or
+/// `addresses` allows any.
+pub struct PeerConfig {
+    pub origin_host: String,
+    pub origin_realm: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// What a connected peer told about itself in its CER.
+#[derive(Clone)]
+pub struct PeerInfo {
+    pub connection: ConnectionHandle,
+    pub origin_realm: String,
+    pub product_name: String,
+    pub vendor_id: u32,
+    pub firmware_revision: Option<u32>,
+}
+
+pub enum Registration {
+    New,
+    /// The peer's other connection has to go.
+    Replaced(PeerInfo),
+    /// The peer keeps its other connection.
+    Refused(PeerInfo),
+}
+
+/// The configured peers, and the ones currently connected keyed by their
+/// Origin-Host in lower case. Shared by all workers.
+pub struct PeerTable {
+    configured: Vec<PeerConfig>,
+    connected: Mutex<HashMap<String, PeerInfo>>,
+}
+
+impl PeerTable {
+    pub fn new(configured: Vec<PeerConfig>) -> Self {
+        PeerTable { configured, connected: Mutex::new(HashMap::new()) }
+    }
+
+    /// True if a peer with this identity may connect from `address`. Any
+    /// peer may if none are configured.
+    pub fn is_allowed(&self, origin_host: &str, origin_realm: &str, address: IpAddr) -> bool {
+        self.configured.is_empty() || self.configured.iter().any(|peer| {
+            peer.origin_host.eq_ignore_ascii_case(origin_host)
+                && (peer.origin_realm.is_empty() || peer.origin_realm.eq_ignore_ascii_case(origin_realm))
+                && (peer.addresses.is_empty() || peer.addresses.contains(&address))
+        })
+    }
+
+    /// Registers a peer that completed the capabilities exchange. If it is
+    /// connected already over another connection, `keep_new` decides which
+    /// of the two connections wins.
+    pub fn register(&self, origin_host: &str, info: PeerInfo, keep_new: bool) -> Registration {
+        let mut connected = self.connected.lock().unwrap();
+        let key = origin_host.to_ascii_lowercase();
+        let address = info.connection.address();
+        match connected.get(&key) {
+            Some(existing) if existing.connection.address() != address && !keep_new => Registration::Refused(existing.clone()),
+            _ => match connected.insert(key, info) {
+                Some(existing) if existing.connection.address() != address => Registration::Replaced(existing),
+                _ => Registration::New,
+            },
+        }
+    }
+
+    /// Forgets a peer, unless its Origin-Host has been taken over by another
+    /// connection meanwhile.
+    pub fn unregister(&self, origin_host: &str, address: SocketAddr) {
+        let mut connected = self.connected.lock().unwrap();
+        let key = origin_host.to_ascii_lowercase();
+        if connected.get(&key).is_some_and(|info| info.connection.address() == address) {
+            connected.remove(&key);
+        }
+    }
+
+    pub fn connected(&self) -> Vec<(String, PeerInfo)> {
+        let mut peers: Vec<(String, PeerInfo)> = self.connected.lock().unwrap().iter()
+            .map(|(host, info)| (host.clone(), info.clone()))
+            .collect();
+        peers.sort_by(|a, b| a.0.cmp(&b.0));
+        peers
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "address={} realm={} product={} vendor={}", self.connection.address(), self.origin_realm, self.product_name, self.vendor_id)?;
+        if let Some(firmware_revision) = self.firmware_revision {
+            write!(f, " firmware={}", firmware_revision)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the connected peers whenever the process gets SIGUSR1.
+pub fn spawn_dumper(table: Arc<PeerTable>) {
+    let mut signals = Signals::new([SIGUSR1]).unwrap();
+    thread::Builder::new()
+        .name("peers".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                let peers = table.connected();
+                println!("Peers: {} connected", peers.len());
+                for (origin_host, info) in peers {
+                    println!("Peer {}: {}", origin_host, info);
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[test]
+pub fn allows_configured_peers_only() {
+    let address = IpAddr::from([10, 0, 0, 1]);
+    let table = PeerTable::new(vec![
+        PeerConfig { origin_host: "pcef.example.com".to_string(), origin_realm: "example.com".to_string(), addresses: vec![address] },
+        PeerConfig { origin_host: "any.example.com".to_string(), origin_realm: String::new(), addresses: vec![] },
+    ]);
+    assert!(table.is_allowed("PCEF.example.com", "example.com", address));
+    assert!(!table.is_allowed("pcef.example.com", "example.com", IpAddr::from([10, 0, 0, 2])));
+    assert!(!table.is_allowed("pcef.example.com", "example.net", address));
+    assert!(table.is_allowed("any.example.com", "example.net", IpAddr::from([10, 0, 0, 2])));
+    assert!(!table.is_allowed("other.example.com", "example.com", address));
+    assert!(PeerTable::new(vec![]).is_allowed("other.example.com", "example.com", address));
+}
+
+#[test]
+pub fn registers_replaces_and_refuses_connections() {
+    let info = |port: u16| PeerInfo {
+        connection: ConnectionHandle::detached(SocketAddr::from(([10, 0, 0, 1], port))),
+        origin_realm: "example.com".to_string(),
+        product_name: String::new(),
+        vendor_id: 0,
+        firmware_revision: None,
+    };
+    let table = PeerTable::new(vec![]);
+    assert!(matches!(table.register("PCEF.example.com", info(1), false), Registration::New));
+    assert!(matches!(table.register("pcef.example.com", info(1), false), Registration::New));
+    match table.register("pcef.example.com", info(2), false) {
+        Registration::Refused(existing) => assert_eq!(1, existing.connection.address().port()),
+        _ => panic!("the old connection should stay"),
+    }
+    match table.register("pcef.example.com", info(2), true) {
+        Registration::Replaced(existing) => assert_eq!(1, existing.connection.address().port()),
+        _ => panic!("the new connection should stay"),
+    }
+    table.unregister("pcef.example.com", SocketAddr::from(([10, 0, 0, 1], 1)));
+    assert_eq!(1, table.connected().len());
+    table.unregister("pcef.example.com", SocketAddr::from(([10, 0, 0, 1], 2)));
+    assert!(table.connected().is_empty());
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counters of one worker. Each worker has its own instance so that they do
+/// not contend on the same cache lines, and so that load per core shows.
+pub struct Stats {
+    answers: AtomicU64,
+    writes: AtomicU64,
+    duplicates: AtomicU64,
+    retransmissions: AtomicU64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Counters {
+    pub answers: u64,
+    pub writes: u64,
+    pub duplicates: u64,
+    pub retransmissions: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats { answers: AtomicU64::new(0), writes: AtomicU64::new(0), duplicates: AtomicU64::new(0), retransmissions: AtomicU64::new(0) }
+    }
+
+    /// Records one write call carrying `answers` answers that had not been
+    /// counted by an earlier (partial) write.
+    pub fn record_write(&self, answers: u64) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.answers.fetch_add(answers, Ordering::Relaxed);
+    }
+
+    /// Records a request that was answered from the duplicate cache.
+    pub fn record_duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request with the T flag set.
+    pub fn record_retransmission(&self) {
+        self.retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            answers: self.answers.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            retransmissions: self.retransmissions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, other: Counters) {
+        self.answers += other.answers;
+        self.writes += other.writes;
+        self.duplicates += other.duplicates;
+        self.retransmissions += other.retransmissions;
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let per_write = if self.writes > 0 { self.answers as f64 / self.writes as f64 } else { 0.0 };
+        write!(f, "answers={} writes={} answers/write={:.2} duplicates={} retransmissions={}",
+               self.answers, self.writes, per_write, self.duplicates, self.retransmissions)
+    }
+}
+
+pub fn spawn_reporter(stats: Vec<Arc<Stats>>, interval: Duration) {
+    thread::Builder::new()
+        .name("stats".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let mut total = Counters::default();
+            for (id, worker_stats) in stats.iter().enumerate() {
+                let counters = worker_stats.counters();
+                println!("Stats worker-{}: {}", id, counters);
+                total += counters;
+            }
+            println!("Stats total: {}", total);
+        })
+        .unwrap();
+}
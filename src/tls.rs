@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::sync::Arc;
+use rustls::{RootCertStore, ServerConfig};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use x509_parser::extensions::GeneralName;
+
+/// Builds the TLS configuration from a PEM certificate chain and a PEM
+/// private key. With `client_ca_path`, peers must present a certificate
+/// issued by one of the CAs in that PEM bundle.
+pub fn load_server_config(cert_path: &str, key_path: &str, client_ca_path: Option<&str>) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let builder = match client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// The host names a certificate is issued for: its DNS subject alternative
+/// names or, if it has none, its subject common names.
+pub fn certificate_names(cert: &CertificateDer) -> Vec<String> {
+    let cert = match x509_parser::parse_x509_certificate(cert) {
+        Ok((_, cert)) => cert,
+        Err(_) => return vec![],
+    };
+    let mut names: Vec<String> = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.iter()
+            .filter_map(|name| match *name {
+                GeneralName::DNSName(dns) => Some(dns.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    if names.is_empty() {
+        names = cert.subject().iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_string())
+            .collect();
+    }
+    names
+}
+
+/// True if `host` is one of `names`, compared without regard to case. A
+/// name starting with `*.` matches any single leftmost label.
+pub fn name_matches(names: &[String], host: &str) -> bool {
+    names.iter().any(|name| {
+        match name.strip_prefix("*.") {
+            Some(suffix) => host.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(suffix)),
+            None => name.eq_ignore_ascii_case(host),
+        }
+    })
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificates in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key in {}", path)))
+}
+
+fn invalid_data(err: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[test]
+pub fn matches_certificate_names() {
+    let names = vec!["pcef.example.com".to_string(), "*.gw.example.com".to_string()];
+    assert!(name_matches(&names, "PCEF.example.com"));
+    assert!(name_matches(&names, "node1.gw.example.com"));
+    assert!(!name_matches(&names, "other.example.com"));
+    assert!(!name_matches(&names, "a.node1.gw.example.com"));
+    assert!(!name_matches(&names, "gw.example.com"));
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use mio::net::TcpStream;
+use rustls::{ServerConfig, ServerConnection};
+use rustls::pki_types::CertificateDer;
+
+/// Plaintext that rustls may hold on to before it refuses more. Keeps a peer
+/// that does not read from growing the buffer, just like for plain TCP.
+const TLS_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// The byte stream of a connection: plain TCP, or TLS on top of it either
+/// from the start or after an in-band upgrade.
+pub struct Transport {
+    stream: TcpStream,
+    tls: Option<Box<ServerConnection>>,
+}
+
+impl Transport {
+    pub fn new(stream: TcpStream, tls_config: Option<&Arc<ServerConfig>>) -> io::Result<Transport> {
+        let mut transport = Transport { stream, tls: None };
+        if let Some(config) = tls_config {
+            transport.start_tls(config, &[])?;
+        }
+        Ok(transport)
+    }
+
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Switches to TLS. `received` is data that was already read from the
+    /// socket but belongs to the TLS handshake.
+    pub fn start_tls(&mut self, config: &Arc<ServerConfig>, received: &[u8]) -> io::Result<()> {
+        let mut tls = ServerConnection::new(config.clone()).map_err(tls_error)?;
+        tls.set_buffer_limit(Some(TLS_BUFFER_LIMIT));
+        let mut received = received;
+        while !received.is_empty() {
+            tls.read_tls(&mut received)?;
+            tls.process_new_packets().map_err(tls_error)?;
+        }
+        self.tls = Some(Box::new(tls));
+        self.flush()
+    }
+
+    /// Reads plaintext. Fails with `WouldBlock` when no more is available.
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let tls = match self.tls {
+            Some(ref mut tls) => tls,
+            None => return self.stream.read(buffer),
+        };
+        loop {
+            match tls.reader().read(buffer) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            if tls.read_tls(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+            let processed = tls.process_new_packets();
+            write_tls(tls, &mut self.stream)?;
+            processed.map_err(tls_error)?;
+        }
+    }
+
+    /// Writes plaintext. Fails with `WouldBlock` when nothing could be taken.
+    pub fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let tls = match self.tls {
+            Some(ref mut tls) => tls,
+            None => return self.stream.write(buffer),
+        };
+        write_tls(tls, &mut self.stream)?;
+        let n = tls.writer().write(buffer)?;
+        write_tls(tls, &mut self.stream)?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        Ok(n)
+    }
+
+    /// Sends as much as possible of what TLS has buffered.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.tls {
+            Some(ref mut tls) => write_tls(tls, &mut self.stream),
+            None => Ok(()),
+        }
+    }
+
+    /// Tells a TLS peer that no more data follows. Best effort, as the
+    /// connection is about to be closed anyway.
+    pub fn close(&mut self) {
+        if let Some(ref mut tls) = self.tls {
+            tls.send_close_notify();
+            let _ = write_tls(tls, &mut self.stream);
+        }
+    }
+
+    /// The certificate a TLS peer authenticated with, once the handshake is
+    /// done.
+    pub fn peer_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.tls.as_ref().and_then(|tls| tls.peer_certificates()).and_then(|certs| certs.first())
+    }
+
+    pub fn has_pending_output(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+}
+
+fn write_tls(tls: &mut ServerConnection, stream: &mut TcpStream) -> io::Result<()> {
+    while tls.wants_write() {
+        match tls.write_tls(stream) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn tls_error(err: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
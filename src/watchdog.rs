@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+use diameter::message_header::HopByHop;
+
+/// Maximum deviation from the configured Tw, so that the DWRs of
+/// connections set up at the same time do not stay in step.
+const JITTER_MILLIS: u64 = 2000;
+
+/// Device-Watchdog state of a connection as described in RFC 3539. A DWR is
+/// sent once nothing has been received for Tw, and another one each Tw for
+/// as long as it goes unanswered.
+pub struct Watchdog {
+    jitter_millis: u64,
+    outstanding: Option<(HopByHop, Instant)>,
+    missed: u32,
+}
+
+impl Watchdog {
+    /// `seed` picks the jitter applied to Tw for this connection.
+    pub fn new(seed: u64) -> Self {
+        Watchdog { jitter_millis: seed % (2 * JITTER_MILLIS + 1), outstanding: None, missed: 0 }
+    }
+
+    /// Number of DWRs in a row that went unanswered within Tw.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    /// True if it is time to send a DWR, after counting a missed answer if
+    /// one was outstanding. `last_received` is when anything at all was last
+    /// received from the peer.
+    pub fn is_due(&mut self, now: Instant, last_received: Instant, interval: Duration) -> bool {
+        let interval = self.jittered(interval);
+        match self.outstanding {
+            Some((_, sent)) if now.duration_since(sent) >= interval => {
+                self.missed += 1;
+                true
+            }
+            Some(_) => false,
+            None => now.duration_since(last_received) >= interval,
+        }
+    }
+
+    pub fn sent(&mut self, hop_by_hop: HopByHop, now: Instant) {
+        self.outstanding = Some((hop_by_hop, now));
+    }
+
+    /// Handles a DWA. Returns the number of answers missed before it if it
+    /// answers the outstanding DWR, or `None` if it is not the expected one.
+    pub fn answered(&mut self, hop_by_hop: HopByHop) -> Option<u32> {
+        match self.outstanding {
+            Some((expected, _)) if expected == hop_by_hop => {
+                self.outstanding = None;
+                let missed = self.missed;
+                self.missed = 0;
+                Some(missed)
+            }
+            _ => None,
+        }
+    }
+
+    fn jittered(&self, interval: Duration) -> Duration {
+        if interval <= Duration::from_millis(2 * JITTER_MILLIS) {
+            return interval;
+        }
+        interval + Duration::from_millis(self.jitter_millis) - Duration::from_millis(JITTER_MILLIS)
+    }
+}
+
+#[test]
+pub fn counts_missed_answers_until_answered() {
+    let start = Instant::now();
+    let tw = Duration::from_secs(30);
+    let mut watchdog = Watchdog::new(JITTER_MILLIS);
+    assert!(!watchdog.is_due(start + Duration::from_secs(29), start, tw));
+    assert!(watchdog.is_due(start + tw, start, tw));
+    watchdog.sent(HopByHop(1), start + tw);
+    assert!(!watchdog.is_due(start + tw + Duration::from_secs(10), start, tw));
+    assert!(watchdog.is_due(start + tw * 2, start, tw));
+    assert_eq!(1, watchdog.missed());
+    watchdog.sent(HopByHop(2), start + tw * 2);
+    assert_eq!(None, watchdog.answered(HopByHop(1)));
+    assert_eq!(Some(1), watchdog.answered(HopByHop(2)));
+    assert_eq!(0, watchdog.missed());
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
+use slab::Slab;
+use core_affinity::{self, CoreId};
+use client::Client;
+use limits::ConnectionLimits;
+use stats::Stats;
+use {ClientError, Config, Endpoint, Shared};
+
+const WAKER: Token = Token(usize::MAX);
+const FIRST_LISTENER: usize = usize::MAX / 2;
+const EVENTS_CAPACITY: usize = 1024;
+/// How often connections are checked for timeouts, which is also how late
+/// a timeout may be noticed.
+const TIMER_TICK: Duration = Duration::from_secs(1);
+
+enum Command {
+    /// Serve a connection accepted on the listener with the given index.
+    Accept(TcpStream, usize),
+    /// Ask the peer of the connection with the given key and address to
+    /// disconnect with the given Disconnect-Cause, if it is still there.
+    Disconnect(usize, SocketAddr, u32),
+    /// Stop accepting, ask all peers to disconnect and exit once they have.
+    Shutdown,
+}
+
+/// Passes commands to a worker thread and wakes it up to handle them.
+#[derive(Clone)]
+struct Mailbox {
+    sender: Sender<Command>,
+    waker: Arc<Waker>,
+}
+
+impl Mailbox {
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.sender.send(command).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.waker.wake()
+    }
+}
+
+/// Handle used to pass new connections and other commands to a worker thread.
+pub struct WorkerHandle {
+    mailbox: Mailbox,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Refers to a connection from outside the worker serving it.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    mailbox: Mailbox,
+    key: usize,
+    address: SocketAddr,
+}
+
+impl ConnectionHandle {
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Makes the worker ask the peer to disconnect with a DPR.
+    pub fn disconnect(&self, cause: u32) {
+        if let Err(e) = self.mailbox.send(Command::Disconnect(self.key, self.address, cause)) {
+            println!("[{}] Failed to request disconnect: {}", self.address, e);
+        }
+    }
+}
+
+#[cfg(test)]
+impl ConnectionHandle {
+    /// A handle to a connection that no worker serves, for tests. Commands
+    /// sent to it are lost.
+    pub fn detached(address: SocketAddr) -> Self {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
+        let (sender, _) = channel();
+        ConnectionHandle { mailbox: Mailbox { sender, waker }, key: 0, address }
+    }
+}
+
+impl WorkerHandle {
+    /// Passes a connection accepted on the listener with the given index.
+    pub fn assign(&self, stream: TcpStream, listener: usize) -> io::Result<()> {
+        self.mailbox.send(Command::Accept(stream, listener))
+    }
+
+    /// Starts a graceful shutdown. The thread ends when it is done.
+    pub fn shutdown(&self) {
+        if let Err(e) = self.mailbox.send(Command::Shutdown) {
+            println!("Failed to shut down worker: {}", e);
+        }
+    }
+
+    pub fn join(self) {
+        let _ = self.thread.join();
+    }
+}
+
+struct Worker {
+    poll: Poll,
+    mailbox: Mailbox,
+    clients: Slab<Client>,
+    receiver: Receiver<Command>,
+    listeners: Vec<TcpListener>,
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    shared: Shared,
+    endpoints: HashMap<(usize, IpAddr), Arc<Endpoint>>,
+    /// Set once shutting down, to when remaining peers are given up on.
+    shutdown_deadline: Option<Instant>,
+}
+
+/// Starts a worker thread. A worker given its own `listeners`, one per
+/// configured listener and in the same order, accepts connections itself.
+/// Otherwise it only serves the ones assigned to it.
+pub fn spawn(id: usize, config: Arc<Config>, stats: Arc<Stats>, shared: Shared, mut listeners: Vec<TcpListener>, core: Option<CoreId>) -> io::Result<WorkerHandle> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    for (index, listener) in listeners.iter_mut().enumerate() {
+        poll.registry().register(listener, Token(FIRST_LISTENER + index), Interest::READABLE)?;
+    }
+    let (sender, receiver) = channel();
+    let mailbox = Mailbox { sender, waker };
+    let worker = Worker { poll, mailbox: mailbox.clone(), clients: Slab::new(), receiver, listeners, config, stats, shared, endpoints: HashMap::new(), shutdown_deadline: None };
+    let thread = thread::Builder::new()
+        .name(format!("worker-{}", id))
+        .spawn(move || {
+            if let Some(core) = core {
+                if !core_affinity::set_for_current(core) {
+                    println!("Failed to pin worker-{} to CPU {}", id, core.id);
+                }
+            }
+            worker.run()
+        })?;
+    Ok(WorkerHandle { mailbox, thread })
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let has_timeouts = self.config.idle_timeout.is_some() || self.config.message_timeout.is_some() || self.config.watchdog_interval.is_some();
+        let mut next_tick = Instant::now() + TIMER_TICK;
+        loop {
+            let mut wake_at = if has_timeouts { Some(next_tick) } else { None };
+            if let Some(deadline) = self.shutdown_deadline {
+                wake_at = Some(wake_at.map_or(deadline, |t| t.min(deadline)));
+            }
+            let timeout = wake_at.map(|t| t.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                println!("Poll failed: {}", e);
+                return;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.handle_commands(),
+                    Token(t) if t >= FIRST_LISTENER => self.accept_own(t - FIRST_LISTENER),
+                    token => self.handle_event(token),
+                }
+            }
+            if has_timeouts && Instant::now() >= next_tick {
+                self.handle_tick();
+                next_tick = Instant::now() + TIMER_TICK;
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                if Instant::now() >= deadline {
+                    let keys: Vec<usize> = self.clients.iter().map(|(key, _)| key).collect();
+                    for key in keys {
+                        self.clients[key].close();
+                        self.remove_client(Token(key), ClientError::ShutdownTimeout);
+                    }
+                }
+                if self.clients.is_empty() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.receiver.try_recv() {
+            match command {
+                // Connections that arrive while shutting down are just closed.
+                Command::Accept(stream, listener) => if self.shutdown_deadline.is_none() {
+                    if let Err(e) = self.add_client(stream, listener) {
+                        println!("Failed to set up client: {}", e);
+                    }
+                },
+                Command::Disconnect(key, address, cause) => self.disconnect(key, address, cause),
+                Command::Shutdown => self.shut_down(),
+            }
+        }
+    }
+
+    fn disconnect(&mut self, key: usize, address: SocketAddr, cause: u32) {
+        let result = match self.clients.get_mut(key) {
+            Some(client) if client.address() == address => client.disconnect(&self.config, &self.stats, cause),
+            _ => return,
+        };
+        if let Err(e) = result {
+            self.remove_client(Token(key), e);
+        }
+    }
+
+    fn shut_down(&mut self) {
+        for mut listener in self.listeners.drain(..) {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+        self.shutdown_deadline = Some(Instant::now() + self.config.shutdown_timeout);
+        let keys: Vec<usize> = self.clients.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            if let Err(e) = self.clients[key].disconnect(&self.config, &self.stats, self.config.disconnect_cause) {
+                self.remove_client(Token(key), e);
+            }
+        }
+    }
+
+    fn accept_own(&mut self, listener: usize) {
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+        loop {
+            match self.listeners[listener].accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.add_client(stream, listener) {
+                        println!("Failed to set up client: {}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    println!("Accept failed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, stream: TcpStream, listener: usize) -> io::Result<()> {
+        let address = stream.peer_addr()?;
+        let permit = match ConnectionLimits::acquire(&self.shared.limits, address.ip()) {
+            Some(permit) => permit,
+            None => {
+                report_disconnect(address, ClientError::TooManyConnections);
+                return Ok(());
+            }
+        };
+        let local_address = stream.local_addr()?.ip();
+        let config = &self.config;
+        let endpoint = self.endpoints.entry((listener, local_address))
+            .or_insert_with(|| Arc::new(Endpoint::new(config, &config.listeners[listener], local_address)))
+            .clone();
+        let entry = self.clients.vacant_entry();
+        let token = Token(entry.key());
+        let connection = ConnectionHandle { mailbox: self.mailbox.clone(), key: token.0, address };
+        let mut client = Client::new(config, stream, connection, endpoint, &self.shared, config.listeners[listener].tls, permit)?;
+        self.poll.registry().register(client.stream(), token, Interest::READABLE | Interest::WRITABLE)?;
+        println!("[{}] Client connected", address);
+        entry.insert(client);
+        Ok(())
+    }
+
+    fn handle_event(&mut self, token: Token) {
+        let result = match self.clients.get_mut(token.0) {
+            Some(client) => client.process(&self.config, &self.stats),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.remove_client(token, e);
+        }
+    }
+
+    fn handle_tick(&mut self) {
+        let now = Instant::now();
+        let (config, stats) = (&self.config, &self.stats);
+        let expired: Vec<(usize, ClientError)> = self.clients.iter_mut()
+            .filter_map(|(key, client)| client.on_tick(config, stats, now).err().map(|e| (key, e)))
+            .collect();
+        for (key, error) in expired {
+            self.clients[key].close();
+            self.remove_client(Token(key), error);
+        }
+    }
+
+    fn remove_client(&mut self, token: Token, error: ClientError) {
+        let mut client = self.clients.remove(token.0);
+        let _ = self.poll.registry().deregister(client.stream());
+        report_disconnect(client.address(), error);
+    }
+}
+
+fn report_disconnect(address: SocketAddr, error: ClientError) {
+    match error {
+        ClientError::DisconnectRequested => {
+            println!("[{}] Client gracefully disconnected", address);
+        }
+        ClientError::IoError(e) => {
+            println!("[{}] I/O Error: {}", address, e);
+        }
+        ClientError::MessageTooLarge(size) => {
+            println!("[{}] Got a too large packet: {}", address, size);
+        }
+        ClientError::TooManyConnections => {
+            println!("[{}] Connection refused: too many connections", address);
+        }
+        ClientError::IdleTimeout => {
+            println!("[{}] Closed idle connection", address);
+        }
+        ClientError::MessageTimeout => {
+            println!("[{}] Timed out waiting for the rest of a message", address);
+        }
+        ClientError::ParseError(e) => {
+            println!("[{}] Packet parsing failed: {}", address, e.description());
+        }
+        ClientError::InvalidHeader(e) => {
+            println!("[{}] Got a message with an invalid header: {}", address, e.description());
+        }
+        ClientError::CapabilitiesExchangeFailed(result_code) => {
+            println!("[{}] Capabilities exchange failed with result code {}", address, result_code);
+        }
+        ClientError::CapabilitiesExchangeMissing(command_code) => {
+            println!("[{}] Got command {} before the capabilities exchange", address, command_code);
+        }
+        ClientError::Disconnected => {
+            println!("[{}] Disconnected by us", address);
+        }
+        ClientError::ShutdownTimeout => {
+            println!("[{}] No DPA before the shutdown timeout", address);
+        }
+        ClientError::WatchdogFailed(missed) => {
+            println!("[{}] No DWA within Tw for {} DWRs in a row", address, missed);
+        }
+        ClientError::CertificateMismatch(origin_host) => {
+            println!("[{}] Client certificate is not issued for {}", address, origin_host);
+        }
+    };
+}